@@ -0,0 +1,496 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer};
+use serde::de::Visitor;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Color { pub r: u8, pub g: u8, pub b: u8, pub a: u8 }
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode { #[default] Normal, Multiply, Overlay }
+
+impl Color {
+    pub fn from_rgba(color: u32) -> Color {
+        let [r, g, b, a] = color.to_be_bytes();
+        Color { r, g, b, a }
+    }
+
+    pub fn from_rgb(color: u32) -> Color {
+        Color::from_rgba(color << 8 | 0xff)
+    }
+
+    pub fn is_transparent(self) -> bool { self.a == 0 }
+
+    /// Compares two colors for use as color map keys. All fully transparent
+    /// colors are considered equal, regardless of their color channels.
+    pub fn matches(self, other: Color) -> bool {
+        self == other || (self.is_transparent() && other.is_transparent())
+    }
+
+    /// Parses `rrggbbaa`, `rrggbb`, `rgba` or `rgb` hex notation, without
+    /// the leading `#`.
+    pub fn from_hex_string(s: &str) -> Option<Color> {
+        if !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        // expand shorthand notation, e.g. f80 to ff8800
+        let expand = || s.chars().flat_map(|c| vec![c, c]).collect::<String>();
+        match s.len() {
+            8 => u32::from_str_radix(s, 16).map(Color::from_rgba).ok(),
+            6 => u32::from_str_radix(s, 16).map(Color::from_rgb).ok(),
+            4 => u32::from_str_radix(&expand(), 16).map(Color::from_rgba).ok(),
+            3 => u32::from_str_radix(&expand(), 16).map(Color::from_rgb).ok(),
+            _ => None,
+        }
+    }
+
+    /// Looks up one of the 16 dye colors by its name, e.g. `light_blue`.
+    pub fn from_dye_name(name: &str) -> Option<Color> {
+        DYE_COLORS.iter().find(|(n, _)| *n == name).map(|&(_, c)| Color::from_rgb(c))
+    }
+
+    /// Looks up one of the 16 chat formatting colors by its name, e.g.
+    /// `dark_aqua`.
+    pub fn from_chat_name(name: &str) -> Option<Color> {
+        CHAT_COLORS.iter().find(|(n, _)| *n == name).map(|&(_, c)| Color::from_rgb(c))
+    }
+
+    /// Converts to floating point, decoding sRGB if `space` is linear.
+    pub fn to_f32(self, space: ColorSpace) -> ColorF {
+        let [r, g, b] = self.rgb_f32();
+        ColorF { r, g, b, a: self.a as f32 / 255.0 }.decode(space)
+    }
+
+    /// Relative luminance in the range 0.0 to 1.0.
+    pub fn luminance(self) -> f32 {
+        let [r, g, b] = self.rgb_f32();
+        0.2126 * r + 0.7152 * g + 0.0722 * b
+    }
+
+    /// Euclidean distance in RGBA space.
+    pub fn distance(self, other: Color) -> f32 {
+        let d = |a: u8, b: u8| (a as f32 - b as f32).powi(2);
+        (d(self.r, other.r) + d(self.g, other.g) + d(self.b, other.b) + d(self.a, other.a)).sqrt()
+    }
+
+    /// Hue (0.0 to 360.0), saturation and value (both 0.0 to 1.0).
+    pub fn to_hsv(self) -> [f32; 3] {
+        rgb_to_hsv(self.rgb_f32())
+    }
+
+    /// Creates an opaque color from hue, saturation and value.
+    pub fn from_hsv(hsv: [f32; 3]) -> Color {
+        let [r, g, b] = hsv_to_rgb(hsv);
+        ColorF { r, g, b, a: 1.0 }.to_color(ColorSpace::Srgb)
+    }
+
+    fn rgb_f32(self) -> [f32; 3] {
+        [self.r as f32 / 255.0, self.g as f32 / 255.0, self.b as f32 / 255.0]
+    }
+}
+
+const DYE_COLORS: [(&str, u32); 16] = [
+    ("white", 0xF9FFFE),
+    ("orange", 0xF9801D),
+    ("magenta", 0xC74EBD),
+    ("light_blue", 0x3AB3DA),
+    ("yellow", 0xFED83D),
+    ("lime", 0x80C71F),
+    ("pink", 0xF38BAA),
+    ("gray", 0x474F52),
+    ("light_gray", 0x9D9D97),
+    ("cyan", 0x169C9C),
+    ("purple", 0x8932B8),
+    ("blue", 0x3C44AA),
+    ("brown", 0x835432),
+    ("green", 0x5E7C16),
+    ("red", 0xB02E26),
+    ("black", 0x1D1D21),
+];
+
+const CHAT_COLORS: [(&str, u32); 16] = [
+    ("black", 0x000000),
+    ("dark_blue", 0x0000AA),
+    ("dark_green", 0x00AA00),
+    ("dark_aqua", 0x00AAAA),
+    ("dark_red", 0xAA0000),
+    ("dark_purple", 0xAA00AA),
+    ("gold", 0xFFAA00),
+    ("gray", 0xAAAAAA),
+    ("dark_gray", 0x555555),
+    ("blue", 0x5555FF),
+    ("green", 0x55FF55),
+    ("aqua", 0x55FFFF),
+    ("red", 0xFF5555),
+    ("light_purple", 0xFF55FF),
+    ("yellow", 0xFFFF55),
+    ("white", 0xFFFFFF),
+];
+
+/// The space color math such as filters, blending and interpolation is done
+/// in.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    /// Operate on the sRGB encoded values directly, like most image editors.
+    #[default]
+    Srgb,
+    /// Operate on linear light values, which is physically correct.
+    Linear,
+}
+
+/// A color with non-premultiplied floating point channels from 0.0 to 1.0,
+/// used as intermediate format while generating images so that rounding only
+/// happens once per pixel.
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct ColorF { pub r: f32, pub g: f32, pub b: f32, pub a: f32 }
+
+impl ColorF {
+    pub const TRANSPARENT: ColorF = ColorF { r: 0.0, g: 0.0, b: 0.0, a: 0.0 };
+
+    /// Converts back to 8 bit sRGB, rounding to the nearest value.
+    pub fn to_color(self, space: ColorSpace) -> Color {
+        let c = self.encode(space);
+        Color { r: f32_to_u8(c.r), g: f32_to_u8(c.g), b: f32_to_u8(c.b), a: f32_to_u8(c.a) }
+    }
+
+    /// Converts sRGB encoded channels into `space`.
+    fn decode(self, space: ColorSpace) -> ColorF {
+        match space {
+            ColorSpace::Srgb => self,
+            ColorSpace::Linear => self.map_rgb(srgb_to_linear),
+        }
+    }
+
+    /// Converts channels in `space` back to sRGB encoding.
+    fn encode(self, space: ColorSpace) -> ColorF {
+        match space {
+            ColorSpace::Srgb => self,
+            ColorSpace::Linear => self.map_rgb(linear_to_srgb),
+        }
+    }
+
+    pub fn is_transparent(self) -> bool { self.a <= 0.0 }
+
+    /// The filter functions below combine the color channels with `other`,
+    /// and blend the result in by `strength` (0.0 to 1.0). The alpha channel
+    /// of this color is kept, except by multiply.
+    pub fn multiply(self, other: ColorF, strength: f32) -> ColorF {
+        let c = self.blend_rgb(other, strength, |a, b| a * b);
+        ColorF { a: self.a + (self.a * other.a - self.a) * strength, ..c }
+    }
+
+    pub fn screen(self, other: ColorF, strength: f32) -> ColorF {
+        self.blend_rgb(other, strength, |a, b| 1.0 - (1.0 - a) * (1.0 - b))
+    }
+
+    pub fn overlay(self, other: ColorF, strength: f32) -> ColorF {
+        self.blend_rgb(other, strength, |a, b| if a < 0.5 { 2.0 * a * b } else { 1.0 - 2.0 * (1.0 - a) * (1.0 - b) })
+    }
+
+    pub fn additive(self, other: ColorF, strength: f32) -> ColorF {
+        self.blend_rgb(other, strength, |a, b| (a + b).min(1.0))
+    }
+
+    /// Replaces the color with `other`, scaled by this color's luminance.
+    pub fn colorize(self, other: ColorF, strength: f32) -> ColorF {
+        let l = self.luminance();
+        self.blend_rgb(other, strength, |_, b| b * l)
+    }
+
+    /// Perceptual adjustments, always done on sRGB values.
+    pub fn shift_hsv(self, space: ColorSpace, hue: f32, saturation: f32, value: f32) -> ColorF {
+        let c = self.encode(space);
+        let [h, s, v] = rgb_to_hsv([c.r, c.g, c.b]);
+        let h = (h + hue).rem_euclid(360.0);
+        let s = (s + saturation).clamp(0.0, 1.0);
+        let v = (v + value).clamp(0.0, 1.0);
+        let [r, g, b] = hsv_to_rgb([h, s, v]);
+        ColorF { r, g, b, a: c.a }.decode(space)
+    }
+
+    pub fn brightness(self, space: ColorSpace, amount: f32) -> ColorF {
+        self.encode(space).map_rgb(|c| (c + amount).clamp(0.0, 1.0)).decode(space)
+    }
+
+    pub fn contrast(self, space: ColorSpace, amount: f32) -> ColorF {
+        self.encode(space).map_rgb(|c| ((c - 0.5) * amount + 0.5).clamp(0.0, 1.0)).decode(space)
+    }
+
+    /// Composites `source` over this color using the given blend mode.
+    pub fn composite(self, source: ColorF, mode: BlendMode) -> ColorF {
+        if self.is_transparent() { return source; }
+
+        let ab = self.a;
+        let a_s = source.a;
+        let blend = |cb: f32, cs: f32| match mode {
+            BlendMode::Normal => cs,
+            BlendMode::Multiply => cb * cs,
+            BlendMode::Overlay => if cb < 0.5 { 2.0 * cb * cs } else { 1.0 - 2.0 * (1.0 - cb) * (1.0 - cs) },
+        };
+
+        let ao = a_s + ab * (1.0 - a_s);
+        let channel = |cb: f32, cs: f32| {
+            let mixed = (1.0 - ab) * cs + ab * blend(cb, cs);
+            (a_s * mixed + ab * (1.0 - a_s) * cb) / ao
+        };
+
+        ColorF {
+            r: channel(self.r, source.r),
+            g: channel(self.g, source.g),
+            b: channel(self.b, source.b),
+            a: ao,
+        }
+    }
+
+    /// Interpolates between two colors, weighting the color channels by
+    /// alpha so that transparent colors don't bleed into the result.
+    pub fn lerp(self, other: ColorF, t: f32) -> ColorF {
+        let a = self.a + (other.a - self.a) * t;
+        if a <= 0.0 { return ColorF::TRANSPARENT; }
+
+        let l = |c1: f32, c2: f32| (c1 * self.a * (1.0 - t) + c2 * other.a * t) / a;
+        ColorF { r: l(self.r, other.r), g: l(self.g, other.g), b: l(self.b, other.b), a }
+    }
+
+    pub fn luminance(self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    fn map_rgb(self, op: impl Fn(f32) -> f32) -> ColorF {
+        ColorF { r: op(self.r), g: op(self.g), b: op(self.b), a: self.a }
+    }
+
+    fn blend_rgb(self, other: ColorF, strength: f32, op: impl Fn(f32, f32) -> f32) -> ColorF {
+        let c = |a: f32, b: f32| a + (op(a, b) - a) * strength;
+        ColorF { r: c(self.r, other.r), g: c(self.g, other.g), b: c(self.b, other.b), a: self.a }
+    }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+fn f32_to_u8(f: f32) -> u8 { (f.clamp(0.0, 1.0) * 255.0).round() as u8 }
+
+fn rgb_to_hsv([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+
+    [h, s, max]
+}
+
+fn hsl_to_rgb([h, s, l]: [f32; 3]) -> [f32; 3] {
+    let v = l + s * l.min(1.0 - l);
+    let s = if v == 0.0 { 0.0 } else { 2.0 * (1.0 - l / v) };
+    hsv_to_rgb([h, s, v])
+}
+
+fn hsv_to_rgb([h, s, v]: [f32; 3]) -> [f32; 3] {
+    let c = v * s;
+    let x = c * (1.0 - ((h / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let m = v - c;
+
+    let [r, g, b] = match (h / 60.0) as u32 {
+        0 => [c, x, 0.0],
+        1 => [x, c, 0.0],
+        2 => [0.0, c, x],
+        3 => [0.0, x, c],
+        4 => [x, 0.0, c],
+        _ => [c, 0.0, x],
+    };
+
+    [r + m, g + m, b + m]
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}{:02x}", self.r, self.g, self.b, self.a)
+    }
+}
+
+/// Accepts `#rrggbb`, `#rrggbbaa`, `#rgb` and `#rgba` hex notation,
+/// `rgb()`, `rgba()`, `hsl()` and `hsla()` functional notation, `dye:<name>`,
+/// `chat:<name>` and `transparent`.
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some(hex) = s.strip_prefix('#') {
+            Color::from_hex_string(hex).ok_or_else(|| format!("invalid hex color '{}'", s))
+        } else if let Some(name) = s.strip_prefix("dye:") {
+            Color::from_dye_name(name).ok_or_else(|| format!("unknown dye color '{}'", name))
+        } else if let Some(name) = s.strip_prefix("chat:") {
+            Color::from_chat_name(name).ok_or_else(|| format!("unknown chat color '{}'", name))
+        } else if let Some(args) = s.strip_suffix(')') {
+            let (func, args) = args.split_at(args.find('(').ok_or_else(|| format!("invalid color '{}'", s))?);
+            parse_function(func.trim(), &args[1..]).map_err(|e| format!("invalid color '{}': {}", s, e))
+        } else if s == "transparent" {
+            Ok(Color::from_rgba(0x00000000))
+        } else {
+            Err(format!("invalid color '{}'", s))
+        }
+    }
+}
+
+/// Parses the arguments of `rgb()` and `hsl()` style colors, separated by
+/// commas or spaces, optionally with the alpha value after a slash.
+fn parse_function(func: &str, args: &str) -> Result<Color, String> {
+    let args: Vec<&str> = args.split(|c: char| c == ',' || c == '/' || c.is_whitespace()).filter(|a| !a.is_empty()).collect();
+    if args.len() != 3 && args.len() != 4 {
+        return Err(format!("expected 3 or 4 arguments, got {}", args.len()));
+    }
+
+    let alpha = args.get(3).map(|a| parse_value(a, 1.0)).transpose()?.unwrap_or(1.0);
+
+    let [r, g, b] = match func {
+        "rgb" | "rgba" => {
+            let c = |a: &str| parse_value(a, 255.0);
+            [c(args[0])?, c(args[1])?, c(args[2])?]
+        }
+        "hsl" | "hsla" => {
+            let h = args[0].strip_suffix("deg").unwrap_or(args[0]).parse::<f32>().map_err(|_| format!("invalid hue '{}'", args[0]))?;
+            let s = parse_value(args[1], 100.0)?;
+            let l = parse_value(args[2], 100.0)?;
+            hsl_to_rgb([h.rem_euclid(360.0), s, l])
+        }
+        _ => return Err(format!("unknown function '{}'", func)),
+    };
+
+    Ok(ColorF { r, g, b, a: alpha }.to_color(ColorSpace::Srgb))
+}
+
+/// Parses a number from 0 to `max` or a percentage, to a value from 0.0 to
+/// 1.0.
+fn parse_value(s: &str, max: f32) -> Result<f32, String> {
+    let (v, max) = match s.strip_suffix('%') {
+        Some(v) => (v, 100.0),
+        None => (s, max),
+    };
+    let v = v.parse::<f32>().map_err(|_| format!("invalid number '{}'", s))?;
+    if !(0.0..=max).contains(&v) {
+        return Err(format!("'{}' out of range", s));
+    }
+    Ok(v / max)
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where
+        D: Deserializer<'de> {
+        deserializer.deserialize_any(ColorVisitor)
+    }
+}
+
+struct ColorVisitor;
+
+impl<'de> Visitor<'de> for ColorVisitor {
+    type Value = Color;
+
+    fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "a color string or 0xRRGGBB integer")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        if v > 0xFFFFFF {
+            return Err(E::custom(format!("color {:#x} out of range, expected 0xRRGGBB", v)));
+        }
+        Ok(Color::from_rgb(v as u32))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        if v < 0 {
+            return Err(E::custom(format!("color {} out of range, expected 0xRRGGBB", v)));
+        }
+        self.visit_u64(v as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> ColorF { s.parse::<Color>().unwrap().to_f32(ColorSpace::Srgb) }
+
+    fn rgba(c: u32) -> Color { Color::from_rgba(c) }
+
+    #[test]
+    fn parse_hex() {
+        assert_eq!("#abc".parse(), Ok(rgba(0xaabbccff)));
+        assert_eq!("#abcd".parse(), Ok(rgba(0xaabbccdd)));
+        assert_eq!("#a1b2c3".parse(), Ok(rgba(0xa1b2c3ff)));
+        assert_eq!(" #a1b2c380 ".parse(), Ok(rgba(0xa1b2c380)));
+        assert!("#abcde".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn parse_functions() {
+        assert_eq!("rgb(255, 128, 0)".parse(), Ok(rgba(0xff8000ff)));
+        assert_eq!("rgb(255 128 0 / 50%)".parse(), Ok(rgba(0xff800080)));
+        assert_eq!("rgba(100%, 0%, 0%, 0.25)".parse(), Ok(rgba(0xff000040)));
+        assert_eq!("hsl(120, 100%, 50%)".parse(), Ok(rgba(0x00ff00ff)));
+        assert_eq!("hsl(240deg 100% 50% / 0.5)".parse(), Ok(rgba(0x0000ff80)));
+        assert_eq!("hsla(0, 0%, 100%, 1)".parse(), Ok(rgba(0xffffffff)));
+        for s in ["rgb(1, 2)", "rgb(300, 0, 0)", "hsv(1, 2, 3)", "rgb(1, 2, 3"].iter() {
+            assert!(s.parse::<Color>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_names() {
+        assert_eq!("dye:red".parse(), Ok(rgba(0xb02e26ff)));
+        assert_eq!("chat:gold".parse(), Ok(rgba(0xffaa00ff)));
+        assert_eq!("transparent".parse(), Ok(rgba(0x00000000)));
+        assert!("dye:nope".parse::<Color>().is_err());
+        assert!("blue".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn deserialize_integer() {
+        assert_eq!(serde_yaml::from_str::<Color>("16744448").unwrap(), rgba(0xff8000ff));
+    }
+
+    #[test]
+    fn multiply_scales_alpha() {
+        let c = hex("#123456").multiply(hex("#80808080"), 1.0).to_color(ColorSpace::Srgb);
+        assert_eq!(c, Color { r: 0x09, g: 0x1a, b: 0x2b, a: 0x80 });
+
+        let c = hex("#123456").multiply(hex("transparent"), 1.0).to_color(ColorSpace::Srgb);
+        assert_eq!(c.a, 0);
+    }
+
+    #[test]
+    fn filter_strength() {
+        let c = hex("#204060").multiply(hex("#000000"), 0.5).to_color(ColorSpace::Srgb);
+        assert_eq!(c, Color { r: 0x10, g: 0x20, b: 0x30, a: 0xff });
+
+        // screen ignores the alpha of its color
+        let c = hex("#000000").screen(hex("#ffffff00"), 1.0).to_color(ColorSpace::Srgb);
+        assert_eq!(c, Color { r: 0xff, g: 0xff, b: 0xff, a: 0xff });
+    }
+}
\ No newline at end of file
@@ -0,0 +1,637 @@
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use crate::color::{BlendMode, Color, ColorF, ColorSpace};
+use crate::error::{Error, Result};
+use crate::inherit;
+use crate::palette::Palette;
+
+#[derive(Debug, Deserialize)]
+#[serde(from = "ColorMapDef")]
+pub struct ColorMap {
+    pub layers: Vec<Layer>,
+    pub animation: Option<Animation>,
+    pub color_space: ColorSpace,
+    /// The files this color map was loaded from, including those referenced
+    /// by `extends` and `include`.
+    pub files: Vec<PathBuf>,
+}
+
+impl ColorMap {
+    /// Parses a color map. Files referenced by `extends` or `include` are
+    /// looked up relative to the working directory.
+    pub fn from_yaml(s: &str) -> Result<ColorMap> {
+        let (value, files) = inherit::from_str(s, Path::new(""))?;
+        Ok(ColorMap { files, ..ColorMap::from_value(value)? })
+    }
+
+    pub fn load(path: &Path) -> Result<ColorMap> {
+        let (value, files) = inherit::load(path)?;
+        Ok(ColorMap { files, ..ColorMap::from_value(value)? })
+    }
+
+    /// Creates a color map that recolors an image by replacing each color of
+    /// `from` with the color at the same position in `to`, keeping all other
+    /// colors.
+    pub fn palette_swap(from: &Palette, to: &Palette) -> Result<ColorMap> {
+        if from.colors.len() != to.colors.len() {
+            return Err(Error::InvalidColorMap(format!("can't swap palettes of different sizes ({} and {} colors)", from.colors.len(), to.colors.len())));
+        }
+
+        let entry = |color: Option<Color>, fallback: bool, source: ColorSource| ColorMapEntry {
+            color,
+            tolerance: None,
+            range: None,
+            fallback: Some(fallback),
+            filters: Vec::new(),
+            source,
+            surface: Surface::default(),
+        };
+
+        let mut rules: Vec<ColorMapEntry> = from.colors.iter().zip(to.colors.iter())
+            .map(|(&f, &t)| entry(Some(f), false, ColorSource::Fill(t)))
+            .collect();
+        rules.push(entry(None, true, ColorSource::Input));
+
+        Ok(ColorMap {
+            layers: vec![Layer { mask: None, blend: BlendMode::Normal, matching: MatchMode::Exact, rules }],
+            animation: None,
+            color_space: ColorSpace::Srgb,
+            files: Vec::new(),
+        })
+    }
+
+    fn from_value(value: Value) -> Result<ColorMap> {
+        let map: ColorMap = serde_yaml::from_value(value.clone())
+            .map_err(|e| diagnose(&value).unwrap_or(Error::ColorMap(e)))?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    /// Checks for problems that can't be caught while parsing.
+    pub fn validate(&self) -> Result<()> {
+        for entry in self.layers.iter().flat_map(|l| l.rules.iter()) {
+            entry.source.validate()?;
+            entry.surface.validate()?;
+            entry.filters.iter().try_for_each(ColorFilter::validate)?;
+        }
+        Ok(())
+    }
+
+    pub fn masks(&self) -> impl Iterator<Item = &Path> {
+        self.layers.iter().filter_map(|l| l.mask.as_deref())
+    }
+
+    /// All images referenced by this color map, including layer masks.
+    pub fn images(&self) -> Vec<&Path> {
+        let mut vec = Vec::new();
+        for layer in self.layers.iter() {
+            vec.extend(layer.mask.as_deref());
+            for entry in layer.rules.iter() {
+                entry.source.collect_images(&mut vec);
+            }
+        }
+        vec
+    }
+}
+
+/// Finds the layer, rule and field a normalized color map failed to parse
+/// at, since the errors for untagged and flattened types don't include it.
+fn diagnose(value: &Value) -> Option<Error> {
+    let error = |msg: String| Some(Error::InvalidColorMap(msg));
+
+    for (key, v) in value.as_mapping()?.iter() {
+        let result = match key.as_str()? {
+            "animation" => check::<Animation>(v),
+            "color-space" => check::<ColorSpace>(v),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            return error(format!("field '{}': {}", key.as_str()?, e));
+        }
+    }
+
+    for (i, layer) in value.get("layers")?.as_sequence()?.iter().enumerate() {
+        for (j, rule) in layer.get("rules").and_then(Value::as_sequence).into_iter().flatten().enumerate() {
+            if let Err(e) = check::<ColorMapEntry>(rule) {
+                let field = rule.as_mapping()?.iter()
+                    .find_map(|(k, v)| check_rule_field(k.as_str()?, v).err().map(|e| (k.as_str().unwrap(), e)));
+                return match field {
+                    Some((k, e)) => error(format!("layer {}, rule {}, field '{}': {}", i, j, k, e)),
+                    None => error(format!("layer {}, rule {}: {}", i, j, e)),
+                };
+            }
+        }
+
+        if let Err(e) = check::<Layer>(layer) {
+            return error(format!("layer {}: {}", i, e));
+        }
+    }
+
+    None
+}
+
+fn check_rule_field(key: &str, value: &Value) -> std::result::Result<(), String> {
+    match key {
+        "color" => check::<Color>(value),
+        "tolerance" => check::<Tolerance>(value),
+        "range" => check::<ColorRange>(value),
+        "fallback" => check::<bool>(value),
+        "filters" => check::<Vec<ColorFilter>>(value),
+        "emissive" | "roughness" | "metalness" | "height" => check::<f32>(value),
+        _ => {
+            let mut source = Mapping::new();
+            source.insert(Value::String(key.to_string()), value.clone());
+            check::<ColorSource>(&Value::Mapping(source))
+        }
+    }
+}
+
+fn check<T: DeserializeOwned>(value: &Value) -> std::result::Result<(), String> {
+    serde_yaml::from_value::<T>(value.clone()).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// A color map is either a plain list of rules applied to the input image,
+/// a single layer, or a stack of layers.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorMapDef {
+    Rules(Vec<ColorMapEntry>),
+    Layers {
+        layers: Vec<Layer>,
+        animation: Option<Animation>,
+        #[serde(default, rename = "color-space")]
+        color_space: ColorSpace,
+    },
+    Layer {
+        #[serde(flatten)]
+        layer: Layer,
+        animation: Option<Animation>,
+        #[serde(default, rename = "color-space")]
+        color_space: ColorSpace,
+    },
+}
+
+impl From<ColorMapDef> for ColorMap {
+    fn from(def: ColorMapDef) -> Self {
+        match def {
+            ColorMapDef::Rules(rules) => ColorMap {
+                layers: vec![Layer { mask: None, blend: BlendMode::Normal, matching: MatchMode::Exact, rules }],
+                animation: None,
+                color_space: ColorSpace::Srgb,
+                files: Vec::new(),
+            },
+            ColorMapDef::Layers { layers, animation, color_space } => ColorMap { layers, animation, color_space, files: Vec::new() },
+            ColorMapDef::Layer { layer, animation, color_space } => ColorMap { layers: vec![layer], animation, color_space, files: Vec::new() },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+pub struct Animation {
+    /// The number of frames to generate, by default the highest frame count
+    /// of all used images and frame sources.
+    pub frames: Option<u32>,
+    pub frametime: Option<u32>,
+    #[serde(default)]
+    pub interpolate: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Layer {
+    /// The mask to select rules by, the input image if not set.
+    pub mask: Option<PathBuf>,
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default, rename = "match")]
+    pub matching: MatchMode,
+    pub rules: Vec<ColorMapEntry>,
+}
+
+/// What to do with mask colors that no rule matches.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Use the fallback rule.
+    #[default]
+    Exact,
+    /// Use the rule with the closest color.
+    Nearest,
+}
+
+impl Layer {
+    pub fn fallback(&self) -> Option<&ColorMapEntry> {
+        self.rules.iter().find(|a| a.fallback.unwrap_or(false))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ColorMapEntry {
+    pub color: Option<Color>,
+    pub tolerance: Option<Tolerance>,
+    pub range: Option<ColorRange>,
+    pub fallback: Option<bool>,
+    #[serde(default)]
+    pub filters: Vec<ColorFilter>,
+    #[serde(flatten)]
+    pub source: ColorSource,
+    #[serde(flatten)]
+    pub surface: Surface,
+}
+
+/// Material properties for the companion maps, all in the range 0.0 to 1.0.
+/// Unset properties are taken from lower layers.
+#[derive(Debug, Default, Copy, Clone, Deserialize)]
+pub struct Surface {
+    pub emissive: Option<f32>,
+    pub roughness: Option<f32>,
+    pub metalness: Option<f32>,
+    /// Height of the surface, with 1.0 being the top.
+    pub height: Option<f32>,
+}
+
+impl Surface {
+    fn validate(&self) -> Result<()> {
+        let props = [("emissive", self.emissive), ("roughness", self.roughness), ("metalness", self.metalness), ("height", self.height)];
+        for (name, value) in props.iter() {
+            if value.is_some_and(|v| !(0.0..=1.0).contains(&v)) {
+                return Err(Error::InvalidColorMap(format!("{} must be between 0 and 1", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ColorMapEntry {
+    pub fn matches_tolerance(&self, color: Color) -> bool {
+        match (self.color, self.tolerance) {
+            (Some(c), Some(Tolerance::Rgb(t))) => c.distance(color) <= t,
+            (Some(c), Some(Tolerance::Hsv { hsv: [th, ts, tv] })) => {
+                let [h1, s1, v1] = c.to_hsv();
+                let [h2, s2, v2] = color.to_hsv();
+                let dh = (h1 - h2).abs();
+                c.a == color.a && dh.min(360.0 - dh) <= th && (s1 - s2).abs() <= ts && (v1 - v2).abs() <= tv
+            }
+            _ => false,
+        }
+    }
+
+    pub fn matches_range(&self, color: Color) -> bool {
+        self.range.is_some_and(|r| r.contains(color))
+    }
+}
+
+/// Allowed deviation from a rule's color, either as distance in RGBA space
+/// (0-255 per channel) or as maximum difference per HSV channel.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Tolerance {
+    Rgb(f32),
+    Hsv { hsv: [f32; 3] },
+}
+
+/// Matches colors with every channel between the respective channels of
+/// `from` and `to`.
+#[derive(Debug, Copy, Clone, Deserialize)]
+pub struct ColorRange {
+    pub from: Color,
+    pub to: Color,
+}
+
+impl ColorRange {
+    pub fn contains(&self, color: Color) -> bool {
+        let in_range = |a: u8, b: u8, v: u8| a.min(b) <= v && v <= a.max(b);
+        in_range(self.from.r, self.to.r, color.r) &&
+            in_range(self.from.g, self.to.g, color.g) &&
+            in_range(self.from.b, self.to.b, color.b) &&
+            in_range(self.from.a, self.to.a, color.a)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSource {
+    Fill(Color),
+    Image(ImageSource),
+    Gradient(Gradient),
+    Palette(PaletteSource),
+    /// Uses a different source for each animation frame.
+    Frames(Vec<ColorSource>),
+    /// Passes the mask color through unchanged.
+    Input,
+}
+
+impl ColorSource {
+    fn validate(&self) -> Result<()> {
+        match self {
+            ColorSource::Gradient(g) => g.validate(),
+            ColorSource::Palette(p) => p.validate(),
+            ColorSource::Frames(sources) if sources.is_empty() => Err(Error::InvalidColorMap("frame source has no frames".to_string())),
+            ColorSource::Frames(sources) => sources.iter().try_for_each(|s| s.validate()),
+            _ => Ok(()),
+        }
+    }
+
+    fn collect_images<'a>(&'a self, vec: &mut Vec<&'a Path>) {
+        match self {
+            ColorSource::Image(src) => vec.push(&src.path),
+            ColorSource::Frames(sources) => sources.iter().for_each(|s| s.collect_images(vec)),
+            _ => {}
+        }
+    }
+
+    /// Whether this source uses the gray levels of the mask pixels matched by
+    /// its rule.
+    pub fn uses_levels(&self) -> bool {
+        match self {
+            ColorSource::Palette(p) => p.levels.is_none(),
+            ColorSource::Frames(sources) => sources.iter().any(|s| s.uses_levels()),
+            _ => false,
+        }
+    }
+
+    /// The number of frames this source defines.
+    pub fn frame_count(&self) -> u32 {
+        match self {
+            ColorSource::Frames(sources) => sources.iter().map(|s| s.frame_count()).fold(sources.len() as u32, u32::max),
+            _ => 1,
+        }
+    }
+}
+
+/// An image sampled at each output pixel. If the image size differs from the
+/// mask size, it is scaled to fit unless tiling is enabled.
+#[derive(Debug, Deserialize)]
+#[serde(from = "ImageSourceDef")]
+pub struct ImageSource {
+    pub path: PathBuf,
+    /// Magnification of the source image, by default 1 when tiling and the
+    /// mask size divided by the image size otherwise.
+    pub scale: Option<[f32; 2]>,
+    pub tile: bool,
+    /// Offset into the source image, in source pixels.
+    pub offset: [f32; 2],
+    pub filter: Filter,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ImageSourceDef {
+    Path(PathBuf),
+    Full {
+        path: PathBuf,
+        scale: Option<Scale>,
+        #[serde(default)]
+        tile: bool,
+        #[serde(default)]
+        offset: [f32; 2],
+        #[serde(default)]
+        filter: Filter,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scale {
+    Uniform(f32),
+    Separate([f32; 2]),
+}
+
+impl From<ImageSourceDef> for ImageSource {
+    fn from(def: ImageSourceDef) -> Self {
+        match def {
+            ImageSourceDef::Path(path) => ImageSource { path, scale: None, tile: false, offset: [0.0, 0.0], filter: Filter::Nearest },
+            ImageSourceDef::Full { path, scale, tile, offset, filter } => {
+                let scale = scale.map(|s| match s {
+                    Scale::Uniform(s) => [s, s],
+                    Scale::Separate(s) => s,
+                });
+                ImageSource { path, scale, tile, offset, filter }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    #[default]
+    Nearest,
+    Linear,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Gradient {
+    #[serde(default)]
+    pub by: GradientInput,
+    pub stops: Vec<GradientStop>,
+}
+
+impl Gradient {
+    /// The position and color of each stop. Stops without an explicit
+    /// position are spaced evenly.
+    fn positions(&self) -> Vec<(f32, Color)> {
+        let len = self.stops.len();
+        self.stops.iter().enumerate().map(|(i, s)| match *s {
+            GradientStop::Color(c) => (if len > 1 { i as f32 / (len - 1) as f32 } else { 0.0 }, c),
+            GradientStop::At { at, color } => (at, color),
+        }).collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.stops.is_empty() {
+            return Err(Error::InvalidColorMap("gradient has no stops".to_string()));
+        }
+        if self.positions().windows(2).any(|w| w[0].0.partial_cmp(&w[1].0) != Some(Ordering::Less)) {
+            return Err(Error::InvalidColorMap("gradient stops must be in ascending order without duplicates".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Samples the gradient at `t` (0.0 to 1.0).
+    pub fn sample(&self, t: f32, space: ColorSpace) -> ColorF {
+        let stops = self.positions();
+        let len = stops.len();
+
+        match stops.iter().position(|&(at, _)| at > t) {
+            None => stops[len - 1].1.to_f32(space),
+            Some(0) => stops[0].1.to_f32(space),
+            Some(i) => {
+                let (at1, c1) = stops[i - 1];
+                let (at2, c2) = stops[i];
+                c1.to_f32(space).lerp(c2.to_f32(space), (t - at1) / (at2 - at1))
+            }
+        }
+    }
+}
+
+/// Maps the mask's gray levels onto colors, from dark to bright. Unless the
+/// levels are given explicitly, the distinct gray levels of the mask pixels
+/// matched by the rule are used, so N levels map to N colors regardless of
+/// their spacing. With a different number of levels, they are spread evenly
+/// over the colors.
+#[derive(Debug, Deserialize)]
+#[serde(from = "PaletteSourceDef")]
+pub struct PaletteSource {
+    pub colors: Vec<Color>,
+    /// The gray level of the mask for each color.
+    pub levels: Option<Vec<Color>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PaletteSourceDef {
+    Colors(Vec<Color>),
+    Full {
+        colors: Vec<Color>,
+        levels: Option<Vec<Color>>,
+    },
+}
+
+impl From<PaletteSourceDef> for PaletteSource {
+    fn from(def: PaletteSourceDef) -> Self {
+        match def {
+            PaletteSourceDef::Colors(colors) => PaletteSource { colors, levels: None },
+            PaletteSourceDef::Full { colors, levels } => PaletteSource { colors, levels },
+        }
+    }
+}
+
+impl PaletteSource {
+    fn validate(&self) -> Result<()> {
+        if self.colors.is_empty() {
+            return Err(Error::InvalidColorMap("palette has no colors".to_string()));
+        }
+        match &self.levels {
+            Some(levels) if levels.len() != self.colors.len() => {
+                Err(Error::InvalidColorMap(format!("palette has {} colors but {} levels", self.colors.len(), levels.len())))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Picks the color for a mask pixel. `levels` are the distinct gray
+    /// levels of the mask pixels matched by the rule, sorted from dark to
+    /// bright, which are used unless the palette has explicit levels.
+    pub fn pick(&self, color: Color, levels: &[u8]) -> Color {
+        let level = gray_level(color) as i32;
+        let nearest = |levels: &mut dyn Iterator<Item = u8>| {
+            levels.enumerate().min_by_key(|&(_, l)| (l as i32 - level).abs()).map_or(0, |(i, _)| i)
+        };
+
+        let (i, count) = match &self.levels {
+            Some(explicit) => (nearest(&mut explicit.iter().map(|&c| gray_level(c))), explicit.len()),
+            None => (nearest(&mut levels.iter().copied()), levels.len()),
+        };
+
+        let n = self.colors.len();
+        let idx = if count > 1 { (i as f32 * (n - 1) as f32 / (count - 1) as f32).round() as usize } else { 0 };
+        self.colors[idx.min(n - 1)]
+    }
+}
+
+/// The gray level of a mask color, from its luminance.
+pub fn gray_level(color: Color) -> u8 {
+    (color.luminance() * 255.0).round() as u8
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GradientInput {
+    /// The luminance of the mask pixel.
+    #[default]
+    Luminance,
+    /// The horizontal position in the image.
+    X,
+    /// The vertical position in the image.
+    Y,
+    /// The current animation frame.
+    Frame,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum GradientStop {
+    Color(Color),
+    At { at: f32, color: Color },
+}
+
+/// Adjusts the color produced by a rule. Filters that take a color can be
+/// blended in partially with `strength` (0.0 to 1.0).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub enum ColorFilter {
+    /// Multiplies all channels including alpha.
+    Multiply {
+        color: Color,
+        #[serde(default = "full_strength")]
+        strength: f32,
+    },
+    Screen {
+        color: Color,
+        #[serde(default = "full_strength")]
+        strength: f32,
+    },
+    Overlay {
+        color: Color,
+        #[serde(default = "full_strength")]
+        strength: f32,
+    },
+    Add {
+        color: Color,
+        #[serde(default = "full_strength")]
+        strength: f32,
+    },
+    /// Shifts hue (in degrees), saturation and value (both -1.0 to 1.0).
+    Hsv {
+        #[serde(default)]
+        hue: f32,
+        #[serde(default)]
+        saturation: f32,
+        #[serde(default)]
+        value: f32,
+    },
+    Brightness {
+        amount: f32,
+    },
+    Contrast {
+        amount: f32,
+    },
+    /// Tints the color by its grayscale value.
+    Colorize {
+        color: Color,
+        #[serde(default = "full_strength")]
+        strength: f32,
+    },
+}
+
+fn full_strength() -> f32 { 1.0 }
+
+impl ColorFilter {
+    fn validate(&self) -> Result<()> {
+        match self {
+            ColorFilter::Multiply { strength, .. } | ColorFilter::Screen { strength, .. } | ColorFilter::Overlay { strength, .. } |
+            ColorFilter::Add { strength, .. } | ColorFilter::Colorize { strength, .. } if !(0.0..=1.0).contains(strength) => {
+                Err(Error::InvalidColorMap("strength must be between 0 and 1".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn apply(&self, color: ColorF, space: ColorSpace) -> ColorF {
+        match self {
+            ColorFilter::Multiply { color: c, strength } => color.multiply(c.to_f32(space), *strength),
+            ColorFilter::Screen { color: c, strength } => color.screen(c.to_f32(space), *strength),
+            ColorFilter::Overlay { color: c, strength } => color.overlay(c.to_f32(space), *strength),
+            ColorFilter::Add { color: c, strength } => color.additive(c.to_f32(space), *strength),
+            ColorFilter::Hsv { hue, saturation, value } => color.shift_hsv(space, *hue, *saturation, *value),
+            ColorFilter::Brightness { amount } => color.brightness(space, *amount),
+            ColorFilter::Contrast { amount } => color.contrast(space, *amount),
+            ColorFilter::Colorize { color: c, strength } => color.colorize(c.to_f32(space), *strength),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,128 @@
+//! Companion textures for shaders, generated from the surface properties of
+//! the rules that produced each pixel.
+//!
+//! The specular and normal maps follow the LabPBR format. The specular map
+//! stores perceptual smoothness in red, F0 or the metal flag in green and
+//! emission in alpha. The normal map stores the normal in DirectX format in
+//! red and green, no ambient occlusion in blue, and the height in alpha.
+
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+use crate::colormap::{ColorMap, Surface};
+use crate::debug::Trace;
+use crate::gen::frame_count;
+
+/// F0 for non-metals, which is about 4% for most dielectrics.
+const DIELECTRIC_F0: u8 = 10;
+/// The LabPBR green value for metals that use the albedo as F0.
+const METAL: u8 = 255;
+
+#[derive(Default)]
+pub struct Companions {
+    /// The emissive parts of the texture, transparent elsewhere.
+    pub emissive: Option<RgbaImage>,
+    pub specular: Option<RgbaImage>,
+    pub normal: Option<RgbaImage>,
+}
+
+impl Companions {
+    /// The images with the suffix that is added to the texture name for each.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &RgbaImage)> {
+        vec![("_e", &self.emissive), ("_s", &self.specular), ("_n", &self.normal)].into_iter()
+            .filter_map(|(suffix, image)| image.as_ref().map(|i| (suffix, i)))
+    }
+}
+
+/// Whether any rule in the color map sets surface properties.
+pub fn needed(map: &ColorMap) -> bool {
+    map.layers.iter().flat_map(|l| l.rules.iter()).any(|e| {
+        let s = e.surface;
+        s.emissive.is_some() || s.roughness.is_some() || s.metalness.is_some() || s.height.is_some()
+    })
+}
+
+/// Generates the companion maps for a texture generated with `map`. Only
+/// maps for properties that are set by some rule are created.
+pub fn generate(map: &ColorMap, trace: &Trace, texture: &RgbaImage) -> Companions {
+    let rules: Vec<Surface> = map.layers.iter().flat_map(|l| l.rules.iter()).map(|e| e.surface).collect();
+    let any = |f: fn(&Surface) -> bool| rules.iter().any(f);
+
+    let (width, height) = texture.dimensions();
+    let surface = |x: u32, y: u32| resolve(map, trace, x, y);
+
+    let emissive = if any(|s| s.emissive.is_some()) {
+        Some(ImageBuffer::from_fn(width, height, |x, y| {
+            let e = surface(x, y).emissive.unwrap_or(0.0);
+            let [r, g, b, a] = texture.get_pixel(x, y).0;
+            Rgba([r, g, b, (a as f32 * e).round() as u8])
+        }))
+    } else {
+        None
+    };
+
+    let specular = if any(|s| s.emissive.is_some() || s.roughness.is_some() || s.metalness.is_some()) {
+        Some(ImageBuffer::from_fn(width, height, |x, y| {
+            let s = surface(x, y);
+            let smoothness = 1.0 - s.roughness.unwrap_or(1.0).sqrt();
+            let f0 = if s.metalness.unwrap_or(0.0) >= 0.5 { METAL } else { DIELECTRIC_F0 };
+            // 255 means no emission
+            let emission = match s.emissive {
+                Some(e) if e > 0.0 => (e * 254.0).round() as u8,
+                _ => 255,
+            };
+            Rgba([to_u8(smoothness), f0, 0, emission])
+        }))
+    } else {
+        None
+    };
+
+    let normal = if any(|s| s.height.is_some()) {
+        let frame_height = height / frame_count(texture);
+        let heights: Vec<f32> = (0..height).flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| surface(x, y).height.unwrap_or(1.0))
+            .collect();
+
+        // heights are relative to the default parallax depth of a quarter
+        // of the texture size
+        let depth = width as f32 / 4.0;
+
+        Some(ImageBuffer::from_fn(width, height, |x, y| {
+            // wrap around within the frame, since block textures tile
+            let frame_y = y / frame_height * frame_height;
+            let h = |dx: i32, dy: i32| {
+                let sx = (x as i32 + dx).rem_euclid(width as i32) as u32;
+                let sy = frame_y + (y as i32 - frame_y as i32 + dy).rem_euclid(frame_height as i32) as u32;
+                heights[(sy * width + sx) as usize]
+            };
+
+            let dx = (h(1, 0) - h(-1, 0)) / 2.0 * depth;
+            let dy = (h(0, 1) - h(0, -1)) / 2.0 * depth;
+            let len = (dx * dx + dy * dy + 1.0).sqrt();
+            let (nx, ny) = (-dx / len, -dy / len);
+
+            Rgba([to_u8(nx * 0.5 + 0.5), to_u8(ny * 0.5 + 0.5), 255, to_u8(h(0, 0))])
+        }))
+    } else {
+        None
+    };
+
+    Companions { emissive, specular, normal }
+}
+
+/// Combines the surface properties of the rules matched on all layers, with
+/// upper layers taking precedence.
+fn resolve(map: &ColorMap, trace: &Trace, x: u32, y: u32) -> Surface {
+    let mut surface = Surface::default();
+    for (i, layer) in map.layers.iter().enumerate().rev() {
+        if let Some(m) = trace.get(i, x, y) {
+            let s = layer.rules[m.rule].surface;
+            surface.emissive = surface.emissive.or(s.emissive);
+            surface.roughness = surface.roughness.or(s.roughness);
+            surface.metalness = surface.metalness.or(s.metalness);
+            surface.height = surface.height.or(s.height);
+        }
+    }
+    surface
+}
+
+fn to_u8(f: f32) -> u8 { (f.clamp(0.0, 1.0) * 255.0).round() as u8 }
\ No newline at end of file
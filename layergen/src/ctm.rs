@@ -0,0 +1,229 @@
+//! Connected texture sheet generation.
+//!
+//! Each tile is the base texture with the border texture drawn over it where
+//! the block doesn't connect to its neighbor. Which pixels belong to the
+//! border is given by masks: one for the top edge, which is rotated for the
+//! other sides, and optionally one each for the outer and inner top left
+//! corner, which are mirrored for the other corners. The outer corner is
+//! drawn where both adjacent sides are unconnected, the inner corner where
+//! both are connected but the diagonal neighbor isn't. Mask alpha controls
+//! how much of the border is drawn.
+//!
+//! Tiles are numbered by their connections, as a bit set of connected
+//! neighbors: up = 1, right = 2, down = 4, left = 8, up right = 16, down
+//! right = 32, down left = 64 and up left = 128. The 16 tile layout only
+//! uses the sides, so a tile's index is its bit set. The 47 tile layout also
+//! uses the diagonals, but only where both adjacent sides connect since
+//! they make no difference otherwise, and numbers the remaining 47
+//! combinations in ascending order of their bit set. On a sheet, tiles are
+//! placed left to right, top to bottom, 4 per row for 16 tiles and 8 per row
+//! for 47 tiles.
+
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+use image::{GenericImage, ImageBuffer, Rgba, RgbaImage};
+use serde::Deserialize;
+
+use crate::color::{Color, ColorSpace};
+use crate::error::{Error, Result};
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize)]
+#[serde(try_from = "u32")]
+pub enum Layout {
+    /// Connections to the four sides only.
+    Sides,
+    /// Connections to the sides and diagonals.
+    Full,
+}
+
+impl TryFrom<u32> for Layout {
+    type Error = String;
+
+    fn try_from(value: u32) -> std::result::Result<Self, Self::Error> {
+        match value {
+            16 => Ok(Layout::Sides),
+            47 => Ok(Layout::Full),
+            _ => Err(format!("unsupported layout {}, must be 16 or 47", value)),
+        }
+    }
+}
+
+impl Layout {
+    /// The connections of each tile, in tile index order.
+    pub fn tiles(self) -> Vec<Connections> {
+        match self {
+            Layout::Sides => (0..16).map(Connections).collect(),
+            Layout::Full => (0..=255).map(Connections).filter(|c| c.is_canonical()).collect(),
+        }
+    }
+
+    fn columns(self) -> u32 {
+        match self {
+            Layout::Sides => 4,
+            Layout::Full => 8,
+        }
+    }
+}
+
+/// A set of connected neighbors, see the module documentation for the bits.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Connections(pub u8);
+
+impl Connections {
+    pub const UP: u8 = 1;
+    pub const RIGHT: u8 = 2;
+    pub const DOWN: u8 = 4;
+    pub const LEFT: u8 = 8;
+    pub const UP_RIGHT: u8 = 16;
+    pub const DOWN_RIGHT: u8 = 32;
+    pub const DOWN_LEFT: u8 = 64;
+    pub const UP_LEFT: u8 = 128;
+
+    /// The corners in the order up right, down right, down left, up left,
+    /// with their adjacent sides.
+    const CORNERS: [(u8, u8, u8); 4] = [
+        (Connections::UP_RIGHT, Connections::UP, Connections::RIGHT),
+        (Connections::DOWN_RIGHT, Connections::DOWN, Connections::RIGHT),
+        (Connections::DOWN_LEFT, Connections::DOWN, Connections::LEFT),
+        (Connections::UP_LEFT, Connections::UP, Connections::LEFT),
+    ];
+
+    pub fn has(self, bits: u8) -> bool { self.0 & bits == bits }
+
+    /// Whether every diagonal connection is between two connected sides.
+    fn is_canonical(self) -> bool {
+        Connections::CORNERS.iter().all(|&(corner, a, b)| !self.has(corner) || self.has(a | b))
+    }
+}
+
+/// Describes a connected texture to generate. Paths are relative to the
+/// file the description was loaded from.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CtmSpec {
+    pub layout: Layout,
+    /// The texture with every side connected.
+    pub base: PathBuf,
+    /// The texture drawn where sides aren't connected.
+    pub border: PathBuf,
+    /// The border pixels for the top edge.
+    pub edge: PathBuf,
+    /// The border pixels for the top left corner if neither the top nor the
+    /// left side are connected.
+    pub corner: Option<PathBuf>,
+    /// The border pixels for the top left corner if both the top and the
+    /// left side are connected but the top left neighbor isn't.
+    pub inner_corner: Option<PathBuf>,
+}
+
+pub struct CtmImages {
+    pub base: RgbaImage,
+    pub border: RgbaImage,
+    pub edge: RgbaImage,
+    pub corner: Option<RgbaImage>,
+    pub inner_corner: Option<RgbaImage>,
+}
+
+/// Generates the tiles of the layout, in tile index order.
+pub fn generate(layout: Layout, images: &CtmImages) -> Result<Vec<RgbaImage>> {
+    let size = images.base.width();
+    if images.base.height() != size {
+        return Err(Error::InvalidCtm("base texture must be square".to_string()));
+    }
+    let all = [Some(&images.border), Some(&images.edge), images.corner.as_ref(), images.inner_corner.as_ref()];
+    if all.iter().flatten().any(|i| i.dimensions() != (size, size)) {
+        return Err(Error::InvalidCtm("all textures and masks must be the same size as the base texture".to_string()));
+    }
+
+    Ok(layout.tiles().into_iter().map(|c| tile(images, c, layout == Layout::Full)).collect())
+}
+
+/// Places tiles onto a single sheet.
+pub fn sheet(layout: Layout, tiles: &[RgbaImage]) -> RgbaImage {
+    let size = tiles.first().map_or(0, |t| t.width());
+    let columns = layout.columns();
+    let rows = (tiles.len() as u32).div_ceil(columns);
+
+    let mut sheet = ImageBuffer::new(size * columns, size * rows);
+    for (i, tile) in tiles.iter().enumerate() {
+        let i = i as u32;
+        sheet.copy_from(tile, i % columns * size, i / columns * size).unwrap();
+    }
+    sheet
+}
+
+fn tile(images: &CtmImages, c: Connections, inner_corners: bool) -> RgbaImage {
+    let size = images.base.width();
+    let max = size - 1;
+    let alpha = |image: &RgbaImage, x: u32, y: u32| image.get_pixel(x, y).0[3] as f32 / 255.0;
+
+    ImageBuffer::from_fn(size, size, |x, y| {
+        // sample the top edge mask rotated to each side
+        let edges = [
+            (Connections::UP, (x, y)),
+            (Connections::RIGHT, (y, max - x)),
+            (Connections::DOWN, (max - x, max - y)),
+            (Connections::LEFT, (max - y, x)),
+        ];
+        let mut coverage = edges.iter()
+            .filter(|(side, _)| !c.has(*side))
+            .map(|&(_, (mx, my))| alpha(&images.edge, mx, my))
+            .fold(0.0, f32::max);
+
+        // sample the top left corner masks mirrored to each corner
+        for &(corner, a, b) in Connections::CORNERS.iter() {
+            let mx = if b == Connections::RIGHT { max - x } else { x };
+            let my = if a == Connections::DOWN { max - y } else { y };
+
+            let mask = if !c.has(a) && !c.has(b) {
+                images.corner.as_ref()
+            } else if inner_corners && c.has(a | b) && !c.has(corner) {
+                images.inner_corner.as_ref()
+            } else {
+                None
+            };
+
+            if let Some(mask) = mask {
+                coverage = coverage.max(alpha(mask, mx, my));
+            }
+        }
+
+        let base = to_color(images.base.get_pixel(x, y)).to_f32(ColorSpace::Srgb);
+        let border = to_color(images.border.get_pixel(x, y)).to_f32(ColorSpace::Srgb);
+        let result = base.lerp(border, coverage).to_color(ColorSpace::Srgb);
+        Rgba([result.r, result.g, result.b, result.a])
+    })
+}
+
+fn to_color(p: &Rgba<u8>) -> Color {
+    let [r, g, b, a] = p.0;
+    Color { r, g, b, a }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(size: u32, a: u8) -> RgbaImage { ImageBuffer::from_pixel(size, size, Rgba([a, a, a, a])) }
+
+    #[test]
+    fn tile_counts() {
+        assert_eq!(Layout::Sides.tiles().len(), 16);
+        let full = Layout::Full.tiles();
+        assert_eq!(full.len(), 47);
+        assert_eq!(full[0], Connections(0));
+        assert_eq!(full[46], Connections(255));
+        assert!(Layout::try_from(12).is_err());
+    }
+
+    #[test]
+    fn sheet_size() {
+        let images = CtmImages { base: fill(4, 0), border: fill(4, 255), edge: fill(4, 0), corner: None, inner_corner: None };
+        let tiles = generate(Layout::Full, &images).unwrap();
+        assert_eq!(sheet(Layout::Full, &tiles).dimensions(), (32, 24));
+
+        let bad = CtmImages { edge: fill(2, 0), ..images };
+        assert!(generate(Layout::Sides, &bad).is_err());
+    }
+}
\ No newline at end of file
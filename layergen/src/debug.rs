@@ -0,0 +1,161 @@
+//! Records which rule produced each pixel, for finding out why a texture
+//! doesn't come out as expected.
+
+use std::fmt::Write;
+
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+use crate::color::Color;
+use crate::colormap::{ColorMap, ColorMapEntry};
+
+/// Fallback pixel positions listed in the report per mask color.
+const MAX_POSITIONS: usize = 8;
+
+/// How a mask color was matched to a rule.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MatchKind { Exact, Tolerance, Range, Nearest, Fallback }
+
+impl MatchKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            MatchKind::Exact => "exact",
+            MatchKind::Tolerance => "tolerance",
+            MatchKind::Range => "range",
+            MatchKind::Nearest => "nearest",
+            MatchKind::Fallback => "fallback",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RuleMatch {
+    pub rule: usize,
+    pub kind: MatchKind,
+}
+
+/// The rules matched for each pixel of each layer.
+#[derive(Debug)]
+pub struct Trace {
+    width: u32,
+    height: u32,
+    layers: Vec<LayerTrace>,
+}
+
+#[derive(Debug)]
+struct LayerTrace {
+    /// The matched rule for each output pixel, `None` for transparent
+    /// pixels that were skipped.
+    pixels: Vec<Option<RuleMatch>>,
+    masks: Vec<Color>,
+}
+
+impl Trace {
+    pub(crate) fn new(layers: usize, width: u32, height: u32) -> Self {
+        let len = (width * height) as usize;
+        let layer = || LayerTrace { pixels: vec![None; len], masks: vec![Color::from_rgba(0); len] };
+        Trace { width, height, layers: (0..layers).map(|_| layer()).collect() }
+    }
+
+    pub(crate) fn record(&mut self, layer: usize, x: u32, y: u32, mask: Color, m: Option<RuleMatch>) {
+        let idx = (y * self.width + x) as usize;
+        let layer = &mut self.layers[layer];
+        layer.masks[idx] = mask;
+        layer.pixels[idx] = m;
+    }
+
+    pub fn get(&self, layer: usize, x: u32, y: u32) -> Option<RuleMatch> {
+        self.layers[layer].pixels[(y * self.width + x) as usize]
+    }
+
+    /// Renders the matched rules as an image with a distinct color for each
+    /// rule index. Pixels matched by anything but an exact color match are
+    /// drawn darker. Layers are placed next to each other from left to right.
+    pub fn image(&self) -> RgbaImage {
+        let layers = self.layers.len().max(1) as u32;
+        ImageBuffer::from_fn(self.width * layers, self.height, |x, y| {
+            let layer = (x / self.width) as usize;
+            match self.layers.get(layer).and_then(|_| self.get(layer, x % self.width, y)) {
+                None => Rgba([0, 0, 0, 0]),
+                Some(m) => {
+                    let c = rule_color(m.rule, m.kind == MatchKind::Exact);
+                    Rgba([c.r, c.g, c.b, c.a])
+                }
+            }
+        })
+    }
+
+    /// Lists the mask colors seen, how often each rule matched, the rules
+    /// that never matched and which pixels fell back to the fallback rule.
+    pub fn report(&self, map: &ColorMap) -> String {
+        let mut s = String::new();
+
+        for (i, (layer, trace)) in map.layers.iter().zip(self.layers.iter()).enumerate() {
+            match &layer.mask {
+                None => writeln!(s, "layer {} (input image):", i).unwrap(),
+                Some(p) => writeln!(s, "layer {} (mask {}):", i, p.display()).unwrap(),
+            }
+
+            // mask colors in order of first appearance
+            let mut colors: Vec<(Color, Option<RuleMatch>, Vec<usize>)> = Vec::new();
+            for (idx, (&mask, &m)) in trace.masks.iter().zip(trace.pixels.iter()).enumerate() {
+                match colors.iter_mut().find(|(c, _, _)| *c == mask) {
+                    Some((_, _, pixels)) => pixels.push(idx),
+                    None => colors.push((mask, m, vec![idx])),
+                }
+            }
+
+            writeln!(s, "  mask colors:").unwrap();
+            for (color, m, pixels) in colors.iter() {
+                write!(s, "    {} {:>6} px  ", color, pixels.len()).unwrap();
+                match m {
+                    None => writeln!(s, "skipped (transparent)").unwrap(),
+                    Some(m) => writeln!(s, "rule {} ({})", m.rule, m.kind.name()).unwrap(),
+                }
+            }
+
+            writeln!(s, "  rules:").unwrap();
+            for (r, entry) in layer.rules.iter().enumerate() {
+                let count = trace.pixels.iter().filter(|m| m.is_some_and(|m| m.rule == r)).count();
+                let c = rule_color(r, true);
+                write!(s, "    {:>3} {:<24} debug color {}  ", r, describe(entry), c).unwrap();
+                if count == 0 {
+                    writeln!(s, "never matched").unwrap();
+                } else {
+                    writeln!(s, "{} px", count).unwrap();
+                }
+            }
+
+            let fallback: Vec<_> = colors.iter().filter(|(_, m, _)| m.is_some_and(|m| m.kind == MatchKind::Fallback)).collect();
+            if !fallback.is_empty() {
+                writeln!(s, "  fallback pixels:").unwrap();
+                for (color, _, pixels) in fallback {
+                    let positions: Vec<_> = pixels.iter().take(MAX_POSITIONS)
+                        .map(|&idx| format!("({}, {})", idx as u32 % self.width, idx as u32 / self.width))
+                        .collect();
+                    let more = if pixels.len() > MAX_POSITIONS { format!(" and {} more", pixels.len() - MAX_POSITIONS) } else { String::new() };
+                    writeln!(s, "    {} at {}{}", color, positions.join(", "), more).unwrap();
+                }
+            }
+        }
+
+        s
+    }
+}
+
+fn describe(entry: &ColorMapEntry) -> String {
+    match (entry.color, entry.range, entry.fallback) {
+        (_, _, Some(true)) => "fallback".to_string(),
+        (Some(c), _, _) if entry.tolerance.is_some() => format!("{} ~", c),
+        (Some(c), _, _) => c.to_string(),
+        (None, Some(r), _) => format!("{}..{}", r.from, r.to),
+        (None, None, _) => "-".to_string(),
+    }
+}
+
+/// Spreads rule indices around the hue circle using the golden angle, so
+/// that neighboring rules get clearly different colors.
+fn rule_color(rule: usize, exact: bool) -> Color {
+    let hue = (rule as f32 * 137.508).rem_euclid(360.0);
+    let value = if exact { 0.95 } else { 0.5 };
+    Color::from_hsv([hue, 0.75, value])
+}
\ No newline at end of file
@@ -0,0 +1,67 @@
+//! Dependency files for build systems, listing the files each generated
+//! texture was made from.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+/// The files read and written by one job.
+#[derive(Debug, Default, Serialize)]
+pub struct Dependencies {
+    pub outputs: Vec<PathBuf>,
+    pub inputs: Vec<PathBuf>,
+}
+
+impl Dependencies {
+    /// Adds an input, unless it is already listed.
+    pub fn input(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        if !self.inputs.contains(&path) {
+            self.inputs.push(path);
+        }
+    }
+}
+
+/// Writes a dependency file, as JSON if `path` has the `.json` extension
+/// and as Makefile rules otherwise.
+pub fn write(path: &Path, deps: &[Dependencies]) -> io::Result<()> {
+    if path.extension().is_some_and(|ext| ext == "json") {
+        let s = serde_json::to_string_pretty(deps)?;
+        fs::write(path, s)
+    } else {
+        fs::write(path, to_makefile(deps))
+    }
+}
+
+fn to_makefile(deps: &[Dependencies]) -> String {
+    let mut s = String::new();
+    for d in deps.iter() {
+        let outputs: Vec<_> = d.outputs.iter().map(|p| escape(p)).collect();
+        s.push_str(&outputs.join(" "));
+        s.push(':');
+        for input in d.inputs.iter() {
+            s.push_str(" \\\n  ");
+            s.push_str(&escape(input));
+        }
+        s.push('\n');
+    }
+    s
+}
+
+/// Escapes characters that have a special meaning in Makefiles.
+fn escape(path: &Path) -> String {
+    let mut s = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' | '\\' => {
+                s.push('\\');
+                s.push(c);
+            }
+            '$' => s.push_str("$$"),
+            _ => s.push(c),
+        }
+    }
+    s
+}
\ No newline at end of file
@@ -0,0 +1,65 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+
+use crate::color::Color;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A file could not be read or written.
+    Io(PathBuf, io::Error),
+    /// An image could not be decoded or encoded.
+    Image(PathBuf, image::ImageError),
+    /// A PNG image could not be encoded.
+    Png(PathBuf, png::EncodingError),
+    /// An image can't be written in the requested color type.
+    Unrepresentable(PathBuf, &'static str),
+    /// A color map could not be parsed.
+    ColorMap(serde_yaml::Error),
+    /// A color map is syntactically valid but can't be used.
+    InvalidColorMap(String),
+    /// A palette file could not be parsed.
+    InvalidPalette(PathBuf, String),
+    /// A connected texture description can't be used.
+    InvalidCtm(String),
+    /// An image referenced by a color map was not found in any of the image
+    /// source directories.
+    MissingImage(PathBuf),
+    /// A layer mask doesn't have the same size as the input image.
+    SizeMismatch(PathBuf),
+    /// A mask pixel had a color no rule applies to.
+    NoRule { color: Color, x: u32, y: u32 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Image(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Png(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Unrepresentable(path, msg) => write!(f, "{}: {}", path.display(), msg),
+            Error::ColorMap(e) => write!(f, "invalid color map: {}", e),
+            Error::InvalidColorMap(msg) => write!(f, "invalid color map: {}", msg),
+            Error::InvalidPalette(path, msg) => write!(f, "invalid palette {}: {}", path.display(), msg),
+            Error::InvalidCtm(msg) => write!(f, "invalid connected texture: {}", msg),
+            Error::MissingImage(path) => write!(f, "failed to find referenced image {}", path.display()),
+            Error::SizeMismatch(path) => write!(f, "layer mask {} must be same size as the input image", path.display()),
+            Error::NoRule { color, x, y } => write!(f, "no rule found for image color {} at ({}, {})", color, x, y),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, e) => Some(e),
+            Error::Image(_, e) => Some(e),
+            Error::Png(_, e) => Some(e),
+            Error::ColorMap(e) => Some(e),
+            _ => None,
+        }
+    }
+}
\ No newline at end of file
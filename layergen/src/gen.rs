@@ -0,0 +1,463 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+use crate::color::{Color, ColorF, ColorSpace};
+use crate::colormap::{gray_level, ColorMap, ColorSource, Filter, GradientInput, ImageSource, Layer, MatchMode};
+use crate::debug::{MatchKind, RuleMatch, Trace};
+use crate::error::{Error, Result};
+
+/// Source images and layer masks referenced by color maps, loaded once and
+/// shared between all jobs.
+pub struct ImageCache {
+    image_src: Vec<PathBuf>,
+    images: HashMap<PathBuf, RgbaImage>,
+    paths: HashMap<PathBuf, PathBuf>,
+}
+
+impl ImageCache {
+    pub fn new(image_src: Vec<PathBuf>) -> Self {
+        ImageCache {
+            image_src,
+            images: HashMap::new(),
+            paths: HashMap::new(),
+        }
+    }
+
+    /// Loads all images referenced by the color map that aren't loaded yet.
+    pub fn load_sources(&mut self, map: &ColorMap) -> Result<()> {
+        for p in map.images() {
+            self.load(p)?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, p: &Path) -> Result<()> {
+        if self.images.contains_key(p) {
+            return Ok(());
+        }
+
+        for dir in self.image_src.iter() {
+            let path = dir.join(p);
+            if path.is_file() {
+                let img = crate::load_image(&path)?;
+                self.images.insert(p.to_path_buf(), img);
+                self.paths.insert(p.to_path_buf(), path);
+                return Ok(());
+            }
+        }
+
+        Err(Error::MissingImage(p.to_path_buf()))
+    }
+
+    /// Adds an image that has been created in-process, to be referenced as
+    /// `path` from color maps.
+    pub fn insert(&mut self, path: impl Into<PathBuf>, image: RgbaImage) {
+        self.images.insert(path.into(), image);
+    }
+
+    pub fn get(&self, p: &Path) -> Option<&RgbaImage> {
+        self.images.get(p)
+    }
+
+    /// The file an image was loaded from, or `None` for images added with
+    /// [`insert`](ImageCache::insert).
+    pub fn file(&self, p: &Path) -> Option<&Path> {
+        self.paths.get(p).map(PathBuf::as_path)
+    }
+
+    fn require(&self, p: &Path) -> Result<&RgbaImage> {
+        self.get(p).ok_or_else(|| Error::MissingImage(p.to_path_buf()))
+    }
+}
+
+/// How many pixels were matched by each kind of rule matching.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct MatchStats {
+    pub exact: usize,
+    pub tolerance: usize,
+    pub range: usize,
+    pub nearest: usize,
+    pub fallback: usize,
+}
+
+impl MatchStats {
+    pub fn fuzzy(&self) -> usize { self.tolerance + self.range + self.nearest }
+}
+
+/// Returns the number of animation frames in the image. Animated textures
+/// are a vertical strip of square frames.
+pub fn frame_count(image: &RgbaImage) -> u32 {
+    if image.height() > image.width() && image.height().is_multiple_of(image.width()) {
+        image.height() / image.width()
+    } else {
+        1
+    }
+}
+
+/// Returns the number of frames in a mask. Masks are only split into frames
+/// if the input mask is `animated`, otherwise a tall mask is a single frame.
+fn mask_frames(image: &RgbaImage, animated: bool) -> u32 {
+    if animated { frame_count(image) } else { 1 }
+}
+
+/// Returns the number of frames generated for the mask `image`. Unless the
+/// color map sets it, this is the largest frame count of `frames` sources
+/// and, if the mask is `animated`, of the mask and the layer masks. Image
+/// sources don't make the output animated, but animated ones are played
+/// along if it is.
+pub fn output_frames(map: &ColorMap, image: &RgbaImage, animated: bool, images: &ImageCache) -> u32 {
+    map.animation.and_then(|a| a.frames).unwrap_or_else(|| {
+        let masks = map.masks().filter_map(|p| images.get(p)).map(|i| mask_frames(i, animated));
+        let sources = map.layers.iter().flat_map(|l| l.rules.iter()).map(|e| e.source.frame_count());
+        masks.chain(sources).fold(mask_frames(image, animated), u32::max)
+    })
+}
+
+fn get_frame_pixel(image: &RgbaImage, frames: u32, frame: u32, x: u32, y: u32) -> Color {
+    let height = image.height() / frames;
+    let [r, g, b, a] = image.get_pixel(x, frame % frames * height + y).0;
+    Color { r, g, b, a }
+}
+
+struct Context<'a> {
+    images: &'a ImageCache,
+    width: u32,
+    height: u32,
+    frames: u32,
+    /// Whether masks are split into frames.
+    animated: bool,
+    space: ColorSpace,
+}
+
+/// Generates a texture from the input mask `image`. `animated` tells whether
+/// the mask is an animation, i.e. comes with a `.mcmeta` file, rather than
+/// just a tall image. All images referenced by the color map must already be
+/// loaded into `images`.
+pub fn generate(map: &ColorMap, image: &RgbaImage, animated: bool, images: &ImageCache) -> Result<RgbaImage> {
+    generate_with_stats(map, image, animated, images).map(|(image, _)| image)
+}
+
+/// Like [`generate`], but also returns how the mask colors were matched.
+pub fn generate_with_stats(map: &ColorMap, image: &RgbaImage, animated: bool, images: &ImageCache) -> Result<(RgbaImage, MatchStats)> {
+    generate_impl(map, image, animated, images, false).map(|(image, stats, _)| (image, stats))
+}
+
+/// Like [`generate_with_stats`], but also records which rule produced each
+/// pixel.
+pub fn generate_with_trace(map: &ColorMap, image: &RgbaImage, animated: bool, images: &ImageCache) -> Result<(RgbaImage, MatchStats, Trace)> {
+    generate_impl(map, image, animated, images, true).map(|(image, stats, trace)| (image, stats, trace.unwrap()))
+}
+
+fn generate_impl(map: &ColorMap, image: &RgbaImage, animated: bool, images: &ImageCache, with_trace: bool) -> Result<(RgbaImage, MatchStats, Option<Trace>)> {
+    map.validate()?;
+
+    let width = image.width();
+    let height = image.height() / mask_frames(image, animated);
+
+    for p in map.masks() {
+        let i = images.require(p)?;
+        if i.width() != width || i.height() / mask_frames(i, animated) != height {
+            return Err(Error::SizeMismatch(p.to_path_buf()));
+        }
+    }
+
+    for p in map.images() {
+        images.require(p)?;
+    }
+
+    let frames = output_frames(map, image, animated, images);
+
+    let ctx = Context { images, width, height, frames, animated, space: map.color_space };
+    let mut buffer = vec![ColorF::TRANSPARENT; (width * height * frames) as usize];
+    let mut stats = MatchStats::default();
+
+    let mut trace = if with_trace { Some(Trace::new(map.layers.len(), width, height * frames)) } else { None };
+
+    let levels: Vec<Vec<Vec<u8>>> = map.layers.iter()
+        .map(|layer| collect_levels(layer, layer_mask(layer, image, images), &ctx))
+        .collect();
+
+    for frame in 0..frames {
+        for (layer_idx, layer) in map.layers.iter().enumerate() {
+            let mask = layer_mask(layer, image, images);
+            let mask_frames = mask_frames(mask, animated);
+
+            for i in 0..width {
+                for j in 0..height {
+                    let color = get_frame_pixel(mask, mask_frames, frame, i, j);
+
+                    match match_pixel(layer, color) {
+                        // fully transparent pixels stay transparent unless there's an
+                        // explicit rule for them
+                        None if color.is_transparent() => {
+                            if let Some(trace) = trace.as_mut() {
+                                trace.record(layer_idx, i, frame * height + j, color, None);
+                            }
+                        }
+                        None => return Err(Error::NoRule { color, x: i, y: frame * height + j }),
+                        Some((rule, kind)) => {
+                            if let Some(trace) = trace.as_mut() {
+                                trace.record(layer_idx, i, frame * height + j, color, Some(RuleMatch { rule, kind }));
+                            }
+
+                            match kind {
+                                MatchKind::Exact => stats.exact += 1,
+                                MatchKind::Tolerance => stats.tolerance += 1,
+                                MatchKind::Range => stats.range += 1,
+                                MatchKind::Nearest => stats.nearest += 1,
+                                MatchKind::Fallback => stats.fallback += 1,
+                            }
+
+                            let entry = &layer.rules[rule];
+                            let mut result = sample_source(&entry.source, &ctx, color, &levels[layer_idx][rule], frame, i, j);
+                            for filter in entry.filters.iter() {
+                                result = filter.apply(result, ctx.space);
+                            }
+
+                            let idx = ((frame * height + j) * width + i) as usize;
+                            buffer[idx] = buffer[idx].composite(result, layer.blend);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let output_image = ImageBuffer::from_fn(width, height * frames, |x, y| {
+        let c = buffer[(y * width + x) as usize].to_color(ctx.space);
+        Rgba([c.r, c.g, c.b, c.a])
+    });
+
+    Ok((output_image, stats, trace))
+}
+
+/// The mask that selects the rules of a layer. Layer masks have been checked
+/// to be loaded before generating.
+fn layer_mask<'a>(layer: &Layer, image: &'a RgbaImage, images: &'a ImageCache) -> &'a RgbaImage {
+    match &layer.mask {
+        None => image,
+        Some(p) => images.get(p).unwrap(),
+    }
+}
+
+/// Finds the rule for a mask pixel, falling back to nearest color matching
+/// and the fallback rule. Fully transparent pixels only match explicit
+/// rules.
+fn match_pixel(layer: &Layer, color: Color) -> Option<(usize, MatchKind)> {
+    let entry = find_rule(layer, color);
+    if entry.is_some() || color.is_transparent() {
+        return entry;
+    }
+
+    let nearest = if layer.matching == MatchMode::Nearest { find_nearest_rule(layer, color) } else { None };
+    let fallback = layer.rules.iter().position(|e| e.fallback.unwrap_or(false));
+    nearest.map(|e| (e, MatchKind::Nearest)).or(fallback.map(|e| (e, MatchKind::Fallback)))
+}
+
+/// Collects the distinct gray levels of the mask pixels matched by each rule
+/// of the layer, sorted from dark to bright. Only done for rules that use
+/// them.
+fn collect_levels(layer: &Layer, mask: &RgbaImage, ctx: &Context) -> Vec<Vec<u8>> {
+    let mut levels: Vec<BTreeSet<u8>> = vec![BTreeSet::new(); layer.rules.len()];
+    if !layer.rules.iter().any(|e| e.source.uses_levels()) {
+        return vec![Vec::new(); layer.rules.len()];
+    }
+
+    let mask_frames = mask_frames(mask, ctx.animated);
+    for frame in 0..ctx.frames {
+        for i in 0..ctx.width {
+            for j in 0..ctx.height {
+                let color = get_frame_pixel(mask, mask_frames, frame, i, j);
+                if let Some((rule, _)) = match_pixel(layer, color) {
+                    levels[rule].insert(gray_level(color));
+                }
+            }
+        }
+    }
+
+    levels.into_iter().map(|l| l.into_iter().collect()).collect()
+}
+
+/// Finds the index of the rule for `color`, without considering the
+/// fallback rule.
+fn find_rule(layer: &Layer, color: Color) -> Option<(usize, MatchKind)> {
+    // exact matches always take precedence over fuzzy ones
+    if let Some(idx) = layer.rules.iter().position(|e| e.color.is_some_and(|c| c.matches(color))) {
+        return Some((idx, MatchKind::Exact));
+    }
+
+    layer.rules.iter().enumerate().find_map(|(idx, e)| {
+        if e.matches_tolerance(color) {
+            Some((idx, MatchKind::Tolerance))
+        } else if e.matches_range(color) {
+            Some((idx, MatchKind::Range))
+        } else {
+            None
+        }
+    })
+}
+
+fn find_nearest_rule(layer: &Layer, color: Color) -> Option<usize> {
+    layer.rules.iter().enumerate()
+        .filter_map(|(idx, e)| e.color.map(|c| (idx, c.distance(color))))
+        .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        .map(|(idx, _)| idx)
+}
+
+fn sample_source(source: &ColorSource, ctx: &Context, mask_color: Color, levels: &[u8], frame: u32, x: u32, y: u32) -> ColorF {
+    let fraction = |v: u32, len: u32| if len > 1 { v as f32 / (len - 1) as f32 } else { 0.0 };
+
+    match source {
+        ColorSource::Fill(c) => c.to_f32(ctx.space),
+        ColorSource::Input => mask_color.to_f32(ctx.space),
+        ColorSource::Image(src) => sample_image(src, ctx, frame, x, y),
+        ColorSource::Gradient(gradient) => {
+            let t = match gradient.by {
+                GradientInput::Luminance => mask_color.luminance(),
+                GradientInput::X => fraction(x, ctx.width),
+                GradientInput::Y => fraction(y, ctx.height),
+                GradientInput::Frame => fraction(frame, ctx.frames),
+            };
+            gradient.sample(t, ctx.space)
+        }
+        ColorSource::Palette(palette) => palette.pick(mask_color, levels).to_f32(ctx.space),
+        ColorSource::Frames(sources) => {
+            sample_source(&sources[frame as usize % sources.len()], ctx, mask_color, levels, frame, x, y)
+        }
+    }
+}
+
+fn sample_image(src: &ImageSource, ctx: &Context, frame: u32, x: u32, y: u32) -> ColorF {
+    // presence of all images is checked before generating
+    let image = ctx.images.get(&src.path).unwrap();
+    // in a still output, a tall source image is just a tall image
+    let image_frames = if ctx.frames > 1 { frame_count(image) } else { 1 };
+    let (width, height) = (image.width() as i64, (image.height() / image_frames) as i64);
+    let [sx, sy] = src.scale.unwrap_or_else(|| {
+        if src.tile {
+            [1.0, 1.0]
+        } else {
+            [ctx.width as f32 / width as f32, ctx.height as f32 / height as f32]
+        }
+    });
+
+    let u = (x as f32 + 0.5) / sx + src.offset[0];
+    let v = (y as f32 + 0.5) / sy + src.offset[1];
+
+    if !src.tile && (u < 0.0 || v < 0.0 || u >= width as f32 || v >= height as f32) {
+        return ColorF::TRANSPARENT;
+    }
+
+    let fetch = |px: i64, py: i64| {
+        let (px, py) = if src.tile {
+            (px.rem_euclid(width), py.rem_euclid(height))
+        } else {
+            (px.clamp(0, width - 1), py.clamp(0, height - 1))
+        };
+        let [r, g, b, a] = image.get_pixel(px as u32, (frame % image_frames) * height as u32 + py as u32).0;
+        Color { r, g, b, a }.to_f32(ctx.space)
+    };
+
+    match src.filter {
+        Filter::Nearest => fetch(u.floor() as i64, v.floor() as i64),
+        Filter::Linear => {
+            let (u, v) = (u - 0.5, v - 0.5);
+            let (x0, y0) = (u.floor() as i64, v.floor() as i64);
+            let (fx, fy) = (u - u.floor(), v - v.floor());
+            let top = fetch(x0, y0).lerp(fetch(x0 + 1, y0), fx);
+            let bottom = fetch(x0, y0 + 1).lerp(fetch(x0 + 1, y0 + 1), fx);
+            top.lerp(bottom, fy)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(colors: &[u32]) -> RgbaImage {
+        ImageBuffer::from_fn(colors.len() as u32, 1, |x, _| {
+            let [_, r, g, b] = colors[x as usize].to_be_bytes();
+            Rgba([r, g, b, 255])
+        })
+    }
+
+    fn pixels(image: &RgbaImage) -> Vec<u32> {
+        image.pixels().map(|p| u32::from_be_bytes([0, p.0[0], p.0[1], p.0[2]])).collect()
+    }
+
+    #[test]
+    fn palette_uses_mask_levels() {
+        let map = ColorMap::from_yaml(r##"
+- fallback: true
+  palette: ["#ff0000", "#00ff00", "#0000ff", "#ffffff"]
+"##).unwrap();
+        let mask = row(&[0x202020, 0x505050, 0x909090, 0xd0d0d0, 0x505050]);
+        let out = generate(&map, &mask, false, &ImageCache::new(vec![])).unwrap();
+        assert_eq!(pixels(&out), [0xff0000, 0x00ff00, 0x0000ff, 0xffffff, 0x00ff00]);
+    }
+
+    #[test]
+    fn palette_explicit_levels() {
+        let map = ColorMap::from_yaml(r##"
+- fallback: true
+  palette:
+    colors: ["#ff0000", "#00ff00", "#0000ff"]
+    levels: ["#000000", "#404040", "#ffffff"]
+"##).unwrap();
+        let mask = row(&[0x000000, 0x505050, 0xc0c0c0]);
+        let out = generate(&map, &mask, false, &ImageCache::new(vec![])).unwrap();
+        assert_eq!(pixels(&out), [0xff0000, 0x00ff00, 0x0000ff]);
+    }
+
+    #[test]
+    fn tall_image_source_is_not_animation() {
+        let map = ColorMap::from_yaml("- fallback: true\n  image: { path: strip.png, tile: true }").unwrap();
+        let mut images = ImageCache::new(vec![]);
+        images.insert("strip.png", ImageBuffer::from_fn(2, 8, |_, y| Rgba([y as u8 * 10, 0, 0, 255])));
+        let mask = ImageBuffer::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+
+        assert_eq!(output_frames(&map, &mask, false, &images), 1);
+        let out = generate(&map, &mask, false, &images).unwrap();
+        assert_eq!(out.dimensions(), (4, 4));
+        // the whole strip is tiled, not just its first square
+        assert_eq!(out.get_pixel(0, 3).0[0], 30);
+    }
+
+    #[test]
+    fn tall_mask_is_animation_only_if_animated() {
+        let map = ColorMap::from_yaml("- fallback: true\n  gradient: { by: y, stops: ['#000000', '#ffffff'] }").unwrap();
+        let images = ImageCache::new(vec![]);
+        let mask = ImageBuffer::from_pixel(2, 4, Rgba([0, 0, 0, 255]));
+
+        // a still tall mask is a single frame spanning the whole height
+        assert_eq!(output_frames(&map, &mask, false, &images), 1);
+        let out = generate(&map, &mask, false, &images).unwrap();
+        assert_eq!(out.dimensions(), (2, 4));
+        assert_eq!(out.get_pixel(0, 1).0[0], 85);
+        assert_eq!(out.get_pixel(0, 3).0[0], 255);
+
+        assert_eq!(output_frames(&map, &mask, true, &images), 2);
+        let out = generate(&map, &mask, true, &images).unwrap();
+        assert_eq!(out.dimensions(), (2, 4));
+        assert_eq!(out.get_pixel(0, 1).0[0], 255);
+    }
+
+    #[test]
+    fn unsorted_gradient_stops() {
+        let stops = [
+            "[{ at: 0.5, color: '#ff0000' }, { at: 0.2, color: '#0000ff' }]",
+            "[{ at: 0.5, color: '#ff0000' }, { at: 0.5, color: '#0000ff' }]",
+            "['#ff0000', '#00ff00', { at: 0.3, color: '#0000ff' }]",
+        ];
+        for stops in stops.iter() {
+            let result = ColorMap::from_yaml(&format!("- fallback: true\n  gradient: {{ stops: {} }}", stops))
+                .and_then(|map| generate(&map, &row(&[0]), false, &ImageCache::new(vec![])));
+            assert!(matches!(result, Err(Error::InvalidColorMap(_))), "{}", stops);
+        }
+        let map = ColorMap::from_yaml("- fallback: true\n  gradient: { stops: ['#000000', { at: 0.8, color: '#ffffff' }] }").unwrap();
+        assert!(generate(&map, &row(&[0]), false, &ImageCache::new(vec![])).is_ok());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,293 @@
+//! Resolves `extends`, `include` and variables in color map files.
+//!
+//! This works on the YAML document before it is deserialized into a
+//! [`ColorMap`](crate::ColorMap). Every document is first normalized to the
+//! layer list form. Then the base map named by `extends` is taken, each map
+//! listed in `include` is merged on top of it in order, and finally the
+//! document itself. Merging overrides top level keys and layer properties,
+//! and merges layers by position. Rules that have the same color (or range,
+//! or are both fallbacks) replace each other, other rules are appended.
+//!
+//! Variables are collected from all files involved, with values from the
+//! including file taking precedence, and then substituted everywhere, so
+//! that a child map can change the colors used by the rules of its base.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::color::Color;
+use crate::error::{Error, Result};
+
+/// A parsed color map file together with the files it references.
+struct Document {
+    path: Option<PathBuf>,
+    variables: Mapping,
+    body: Mapping,
+    parents: Vec<Document>,
+}
+
+/// Loads a color map file and everything it references into a single
+/// normalized document. Also returns the paths of all files that were read.
+pub fn load(path: &Path) -> Result<(Value, Vec<PathBuf>)> {
+    let doc = load_document(path, &mut Vec::new())?;
+    resolve(doc)
+}
+
+/// Like [`load`], with `extends` and `include` paths relative to `dir`.
+pub fn from_str(s: &str, dir: &Path) -> Result<(Value, Vec<PathBuf>)> {
+    let value = serde_yaml::from_str(s).map_err(Error::ColorMap)?;
+    let doc = parse_document(value, dir, &mut Vec::new())?;
+    resolve(doc)
+}
+
+fn resolve(doc: Document) -> Result<(Value, Vec<PathBuf>)> {
+    let mut variables = Mapping::new();
+    collect_variables(&doc, &mut variables);
+    let mut files = Vec::new();
+    collect_files(&doc, &mut files);
+    let body = merge_document(doc, &variables)?;
+    Ok((Value::Mapping(body), files))
+}
+
+fn load_document(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Document> {
+    let canonical = path.canonicalize().map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    if stack.contains(&canonical) {
+        return Err(Error::InvalidColorMap(format!("cyclic reference to {}", path.display())));
+    }
+
+    let s = fs::read_to_string(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    let value = serde_yaml::from_str(&s).map_err(Error::ColorMap)?;
+
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let doc = parse_document(value, dir, stack);
+    stack.pop();
+    doc.map(|doc| Document { path: Some(path.to_path_buf()), ..doc })
+}
+
+fn parse_document(value: Value, dir: &Path, stack: &mut Vec<PathBuf>) -> Result<Document> {
+    let mut body = normalize(value)?;
+
+    let mut parents = Vec::new();
+    if let Some(extends) = body.remove(&key("extends")) {
+        let path = as_path(&extends, "extends")?;
+        parents.push(load_document(&dir.join(path), stack)?);
+    }
+
+    match body.remove(&key("include")) {
+        None => {}
+        Some(Value::Sequence(seq)) => {
+            for v in seq.iter() {
+                parents.push(load_document(&dir.join(as_path(v, "include")?), stack)?);
+            }
+        }
+        Some(v) => parents.push(load_document(&dir.join(as_path(&v, "include")?), stack)?),
+    }
+
+    let variables = match body.remove(&key("variables")) {
+        None => Mapping::new(),
+        Some(Value::Mapping(m)) => m,
+        Some(_) => return Err(Error::InvalidColorMap("'variables' must be a map".to_string())),
+    };
+
+    Ok(Document { path: None, variables, body, parents })
+}
+
+/// Converts the list of rules and single layer forms to the layer list form.
+fn normalize(value: Value) -> Result<Mapping> {
+    match value {
+        Value::Sequence(rules) => {
+            let mut layer = Mapping::new();
+            layer.insert(key("rules"), Value::Sequence(rules));
+            let mut map = Mapping::new();
+            map.insert(key("layers"), Value::Sequence(vec![Value::Mapping(layer)]));
+            Ok(map)
+        }
+        Value::Mapping(map) if map.contains_key(&key("layers")) => Ok(map),
+        Value::Mapping(map) => {
+            let mut out = Mapping::new();
+            let mut layer = Mapping::new();
+            for (k, v) in map {
+                match k.as_str() {
+                    Some("animation") | Some("color-space") | Some("extends") | Some("include") | Some("variables") => {
+                        out.insert(k, v);
+                    }
+                    _ => {
+                        layer.insert(k, v);
+                    }
+                }
+            }
+
+            // fragments that only define variables or include other files
+            // don't contribute a layer
+            let layers = if layer.is_empty() { vec![] } else { vec![Value::Mapping(layer)] };
+            out.insert(key("layers"), Value::Sequence(layers));
+            Ok(out)
+        }
+        _ => Err(Error::InvalidColorMap("expected a list of rules or a map".to_string())),
+    }
+}
+
+fn collect_variables(doc: &Document, variables: &mut Mapping) {
+    for parent in doc.parents.iter() {
+        collect_variables(parent, variables);
+    }
+    for (k, v) in doc.variables.iter() {
+        variables.insert(k.clone(), v.clone());
+    }
+}
+
+fn collect_files(doc: &Document, files: &mut Vec<PathBuf>) {
+    files.extend(doc.path.clone());
+    for parent in doc.parents.iter() {
+        collect_files(parent, files);
+    }
+}
+
+fn merge_document(doc: Document, variables: &Mapping) -> Result<Mapping> {
+    let mut result = Mapping::new();
+    for parent in doc.parents {
+        let parent = merge_document(parent, variables)?;
+        merge_map(&mut result, parent);
+    }
+
+    let body = substitute(Value::Mapping(doc.body), variables)?;
+    if let Value::Mapping(body) = body {
+        merge_map(&mut result, body);
+    }
+
+    Ok(result)
+}
+
+fn merge_map(base: &mut Mapping, child: Mapping) {
+    for (k, v) in child {
+        match (k.as_str(), base.get_mut(&k)) {
+            (Some("layers"), Some(Value::Sequence(base_layers))) => {
+                if let Value::Sequence(layers) = v {
+                    for (i, layer) in layers.into_iter().enumerate() {
+                        match (base_layers.get_mut(i), layer) {
+                            (Some(Value::Mapping(base_layer)), Value::Mapping(layer)) => merge_layer(base_layer, layer),
+                            (_, layer) => base_layers.push(layer),
+                        }
+                    }
+                }
+            }
+            _ => {
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+fn merge_layer(base: &mut Mapping, child: Mapping) {
+    for (k, v) in child {
+        match (k.as_str(), base.get_mut(&k)) {
+            (Some("rules"), Some(Value::Sequence(base_rules))) => {
+                if let Value::Sequence(rules) = v {
+                    for rule in rules {
+                        match base_rules.iter().position(|r| same_rule(r, &rule)) {
+                            Some(idx) => base_rules[idx] = rule,
+                            None => base_rules.push(rule),
+                        }
+                    }
+                }
+            }
+            _ => {
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+/// Whether `b` should replace `a` when merging rules.
+fn same_rule(a: &Value, b: &Value) -> bool {
+    let is_fallback = |v: &Value| v.get("fallback").and_then(Value::as_bool).unwrap_or(false);
+    if is_fallback(a) || is_fallback(b) {
+        return is_fallback(a) && is_fallback(b);
+    }
+
+    let color = |v: &Value| v.get("color").cloned().map(serde_yaml::from_value::<Color>);
+    match (color(a), color(b)) {
+        (Some(Ok(c1)), Some(Ok(c2))) => c1.matches(c2) && a.get("range") == b.get("range"),
+        (None, None) => a.get("range").is_some() && a.get("range") == b.get("range"),
+        _ => false,
+    }
+}
+
+/// Replaces strings of the form `$name` with the value of the variable.
+fn substitute(value: Value, variables: &Mapping) -> Result<Value> {
+    match value {
+        Value::String(s) if s.starts_with('$') => {
+            variables.get(&key(&s[1..])).cloned()
+                .ok_or_else(|| Error::InvalidColorMap(format!("undefined variable {}", s)))
+        }
+        Value::Sequence(seq) => seq.into_iter().map(|v| substitute(v, variables)).collect::<Result<_>>().map(Value::Sequence),
+        Value::Mapping(map) => {
+            let mut out = Mapping::new();
+            for (k, v) in map {
+                out.insert(k, substitute(v, variables)?);
+            }
+            Ok(Value::Mapping(out))
+        }
+        v => Ok(v),
+    }
+}
+
+fn as_path<'a>(value: &'a Value, field: &str) -> Result<&'a Path> {
+    value.as_str().map(Path::new)
+        .ok_or_else(|| Error::InvalidColorMap(format!("'{}' must be a path", field)))
+}
+
+fn key(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(value: &Value) -> Vec<(String, String)> {
+        let str = |v: &Value, k: &str| v.get(k).and_then(Value::as_str).unwrap_or_default().to_string();
+        value["layers"][0]["rules"].as_sequence().unwrap().iter().map(|r| (str(r, "color"), str(r, "fill"))).collect()
+    }
+
+    #[test]
+    fn override_rules_by_color() {
+        let dir = std::env::temp_dir().join("layergen-test-inherit");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("base.yml"), r##"
+variables:
+  stone: "#808080"
+rules:
+  - { color: "#ff0000", fill: $stone }
+  - { color: "#00ff00", fill: "#222222" }
+  - { fallback: true, fill: "#000000" }
+"##).unwrap();
+
+        let (value, files) = from_str(r##"
+extends: base.yml
+variables:
+  stone: "#909090"
+rules:
+  - { color: "#f00f", fill: "#333333" }
+  - { color: "#0000ff", fill: $stone }
+  - { fallback: true, fill: "#ffffff" }
+"##, &dir).unwrap();
+
+        let expected = [("#f00f", "#333333"), ("#00ff00", "#222222"), ("", "#ffffff"), ("#0000ff", "#909090")];
+        let expected: Vec<_> = expected.iter().map(|&(c, f)| (c.to_string(), f.to_string())).collect();
+        assert_eq!(rules(&value), expected);
+        assert_eq!(files, [dir.join("base.yml")]);
+    }
+
+    #[test]
+    fn cyclic_extends() {
+        let dir = std::env::temp_dir().join("layergen-test-inherit-cycle");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.yml"), "extends: b.yml\nrules: []").unwrap();
+        fs::write(dir.join("b.yml"), "extends: a.yml\nrules: []").unwrap();
+        assert!(matches!(load(&dir.join("a.yml")), Err(Error::InvalidColorMap(_))));
+    }
+}
\ No newline at end of file
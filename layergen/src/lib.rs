@@ -0,0 +1,49 @@
+//! Generates textures by recoloring mask images according to color maps.
+//!
+//! ```no_run
+//! use std::path::{Path, PathBuf};
+//!
+//! use layergen::{ColorMap, ImageCache};
+//!
+//! # fn main() -> layergen::Result<()> {
+//! let map = ColorMap::load(Path::new("planks.yml"))?;
+//! let mask_path = Path::new("planks_mask.png");
+//! let mask = layergen::load_image(mask_path)?;
+//! let animated = layergen::mcmeta::path(mask_path).is_file();
+//!
+//! let mut images = ImageCache::new(vec![PathBuf::from("textures")]);
+//! images.load_sources(&map)?;
+//!
+//! let texture = layergen::generate(&map, &mask, animated, &images)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::Path;
+
+use image::RgbaImage;
+
+pub use color::{BlendMode, Color};
+pub use colormap::ColorMap;
+pub use error::{Error, Result};
+pub use gen::{frame_count, generate, output_frames, generate_with_stats, generate_with_trace, ImageCache, MatchStats};
+pub use palette::Palette;
+
+pub mod color;
+pub mod colormap;
+pub mod companion;
+pub mod ctm;
+pub mod debug;
+pub mod depfile;
+pub mod mcmeta;
+pub mod output;
+pub mod palette;
+
+mod error;
+mod gen;
+mod inherit;
+
+/// Opens an image as RGBA, e.g. to use as input mask.
+pub fn load_image(path: &Path) -> Result<RgbaImage> {
+    image::open(path).map(|i| i.into_rgba()).map_err(|e| Error::Image(path.to_path_buf(), e))
+}
\ No newline at end of file
@@ -1,180 +1,296 @@
 use std::collections::HashMap;
+use std::fs;
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::process;
 
 use clap::{app_from_crate, Arg};
-use image::{ImageBuffer, RgbaImage, Rgba, ImageFormat};
-use serde::{Deserialize, Deserializer};
-use serde::de::Error;
+use image::RgbaImage;
+
+use layergen::{ColorMap, Error, ImageCache, MatchStats, Palette};
+use layergen::companion;
+use layergen::ctm;
+use layergen::ctm::{CtmImages, CtmSpec};
+use layergen::depfile;
+use layergen::depfile::Dependencies;
+use layergen::mcmeta;
+use layergen::output::{Compression, OutputColor, PngOptions};
+
+use crate::manifest::Manifest;
+
+mod manifest;
 
 fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     let matches = app_from_crate!()
         .arg(Arg::with_name("image-src").short('I').value_name("DIR").multiple_occurrences(true))
-        .arg(Arg::with_name("colormap").short('m').value_name("FILE").required(true))
-        .arg(Arg::with_name("output").short('o').value_name("FILE").default_value("a.png"))
-        .arg(Arg::with_name("image").value_name("IMAGE").required(true))
+        .arg(Arg::with_name("colormap").short('m').value_name("FILE").required_unless_one(&["manifest", "palette-from", "ctm"]))
+        .arg(Arg::with_name("palette-from").long("palette-from").value_name("FILE").requires("palette-to").conflicts_with("colormap"))
+        .arg(Arg::with_name("palette-to").long("palette-to").value_name("FILE").requires("palette-from"))
+        .arg(Arg::with_name("output").short('o').value_name("PATH").about("Output file [default: a.png], or output directory with --ctm but without --sheet"))
+        .arg(Arg::with_name("color-type").long("color-type").value_name("TYPE").possible_values(&["auto", "rgba", "rgb", "indexed"]).default_value("auto"))
+        .arg(Arg::with_name("compression").long("compression").value_name("LEVEL").possible_values(&["fast", "default", "best"]).default_value("default"))
+        .arg(Arg::with_name("debug-map").long("debug-map"))
+        .arg(Arg::with_name("depfile").short('M').long("depfile").value_name("FILE"))
+        .arg(Arg::with_name("manifest").short('b').long("manifest").value_name("FILE").conflicts_with_all(&["colormap", "palette-from", "image"]))
+        .arg(Arg::with_name("ctm").long("ctm").value_name("FILE").conflicts_with_all(&["colormap", "palette-from", "manifest", "image"]))
+        .arg(Arg::with_name("sheet").long("sheet").requires("ctm"))
+        .arg(Arg::with_name("image").value_name("IMAGE").required_unless_one(&["manifest", "ctm"]))
         .get_matches();
 
-    let image_src = matches.values_of_os("image-src").map(|v| v.map(Path::new).collect()).unwrap_or_else(|| Vec::new());
-    let colormap = Path::new(matches.value_of_os("colormap").unwrap());
-    let output = Path::new(matches.value_of_os("output").unwrap());
-    let image = Path::new(matches.value_of_os("image").unwrap());
-
-    let map: ColorMap = serde_yaml::from_reader(File::open(colormap).expect("Failed to open color map")).expect("Failed to read color map");
-    let fallback = map.0.iter().filter_map(|a| if a.fallback.unwrap_or(false) { Some(a) } else { None }).next();
-
-    let image = image::open(image).expect("Failed to open input image").into_rgb();
-
-    let mut images: HashMap<&Path, RgbaImage> = HashMap::new();
-    for entry in map.0.iter() {
-        if let ColorSource::Image(p) = &entry.source {
-            if !images.contains_key(&**p) {
-                let mut i = None;
-                for &dir in image_src.iter() {
-                    if let Ok(img) = image::open(dir.join(p)) {
-                        i = Some(img.into_rgba());
-                        break;
-                    }
-                }
+    let png_options = PngOptions {
+        color: OutputColor::from_name(matches.value_of("color-type").unwrap()).unwrap(),
+        compression: Compression::from_name(matches.value_of("compression").unwrap()).unwrap(),
+    };
 
-                match i {
-                    None => {
-                        panic!("Failed to open referenced image {}", p.to_string_lossy());
-                    }
-                    Some(i) => {
-                        if i.width() != image.width() || i.height() != image.height() {
-                            panic!("Included images must be same size");
-                        }
-                        images.insert(p, i);
-                    }
-                }
-            }
-        }
-    }
+    let debug_map = matches.is_present("debug-map");
 
-    let mut output_image: RgbaImage = ImageBuffer::new(image.width(), image.height());
+    let mut image_src: Vec<PathBuf> = matches.values_of_os("image-src").map(|v| v.map(PathBuf::from).collect()).unwrap_or_default();
+    let mut deps: Vec<Dependencies> = Vec::new();
 
-    for i in 0..image.width() {
-        for j in 0..image.height() {
-            let pixel = image.get_pixel(i, j);
-            let [r, g, b] = pixel.0;
-            let color = Color { a: 0xff, r, g, b };
+    let output = matches.value_of_os("output").map(Path::new);
 
-            let mut entry = None;
+    if let Some(ctm) = matches.value_of_os("ctm") {
+        let sheet = matches.is_present("sheet");
+        let output = match output {
+            Some(output) => output,
+            None if sheet => Path::new("a.png"),
+            None => return Err("-o is required to name the output directory for connected texture tiles".into()),
+        };
+        let mut job_deps = Dependencies::default();
+        run_ctm(Path::new(ctm), output, sheet, &png_options, &mut job_deps)?;
+        deps.push(job_deps);
+    } else if let Some(manifest) = matches.value_of_os("manifest") {
+        let manifest_path = Path::new(manifest);
+        let file = File::open(manifest_path).map_err(|e| Error::Io(manifest_path.to_path_buf(), e))?;
+        let manifest: Manifest = serde_yaml::from_reader(file).map_err(|e| format!("invalid manifest {}: {}", manifest_path.display(), e))?;
 
-            for e in map.0.iter() {
-                if let Some(c) = e.color {
-                    if c == color {
-                        entry = Some(e);
-                        break;
-                    }
-                }
-            }
+        // paths in the manifest are relative to the manifest itself
+        let base = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+        image_src.extend(manifest.image_src.iter().map(|p| base.join(p)));
 
-            entry = entry.or(fallback);
-
-            match entry {
-                None => panic!("No rule found for image color #{:02x}{:02x}{:02x}", r, g, b),
-                Some(entry) => {
-                    let mut color = match &entry.source {
-                        ColorSource::Fill(c) => *c,
-                        ColorSource::Image(p) => {
-                            let [r, g, b, a] = images.get(&**p).unwrap().get_pixel(i, j).0;
-                            Color { r, g, b, a }
-                        }
-                    };
-
-                    for filter in entry.filters.iter() {
-                        match filter {
-                            ColorFilter::Multiply { color: c } => {
-                                color = color.multiply(*c);
-                            }
-                        }
-                    }
+        let mut images = ImageCache::new(image_src);
+        let mut colormaps: HashMap<PathBuf, ColorMap> = HashMap::new();
+        let mut masks: HashMap<PathBuf, Mask> = HashMap::new();
+
+        for job in manifest.jobs.iter() {
+            let image = base.join(&job.image);
+            let output = base.join(&job.output);
+
+            let mut job_deps = Dependencies::default();
+            job_deps.input(manifest_path);
+            mask_inputs(&image, &mut job_deps);
 
-                    output_image.put_pixel(i, j, Rgba([color.r, color.g, color.b, color.a]));
+            let swap_map;
+            let map = match (&job.colormap, &job.palette_from, &job.palette_to) {
+                (Some(colormap), None, None) => {
+                    let colormap = base.join(colormap);
+                    if !colormaps.contains_key(&colormap) {
+                        colormaps.insert(colormap.clone(), ColorMap::load(&colormap)?);
+                    }
+                    &colormaps[&colormap]
+                }
+                (None, Some(from), Some(to)) => {
+                    swap_map = palette_swap(&base.join(from), &base.join(to))?;
+                    &swap_map
                 }
+                _ => return Err(format!("job for {} needs either a colormap or palette-from and palette-to", output.display()).into()),
+            };
+
+            if !masks.contains_key(&image) {
+                masks.insert(image.clone(), load_mask(&image)?);
             }
+            let image = &masks[&image];
+
+            images.load_sources(map)?;
+            run_job(&output, map, image, &images, &png_options, debug_map, &mut job_deps)?;
+            deps.push(job_deps);
         }
+    } else {
+        let output = output.unwrap_or_else(|| Path::new("a.png"));
+        let image = Path::new(matches.value_of_os("image").unwrap());
+
+        let mut job_deps = Dependencies::default();
+        mask_inputs(image, &mut job_deps);
+
+        let map = match matches.value_of_os("colormap") {
+            Some(colormap) => ColorMap::load(Path::new(colormap))?,
+            None => palette_swap(Path::new(matches.value_of_os("palette-from").unwrap()), Path::new(matches.value_of_os("palette-to").unwrap()))?,
+        };
+        let image = load_mask(image)?;
+
+        let mut images = ImageCache::new(image_src);
+        images.load_sources(&map)?;
+        run_job(output, &map, &image, &images, &png_options, debug_map, &mut job_deps)?;
+        deps.push(job_deps);
     }
 
-    output_image.save_with_format(output, ImageFormat::Png).unwrap();
-}
+    if let Some(depfile) = matches.value_of_os("depfile") {
+        let depfile = Path::new(depfile);
+        depfile::write(depfile, &deps).map_err(|e| Error::Io(depfile.to_path_buf(), e))?;
+    }
 
-#[derive(Debug, Deserialize)]
-struct ColorMap(Vec<ColorMapEntry>);
-
-#[derive(Debug, Deserialize)]
-struct ColorMapEntry {
-    color: Option<Color>,
-    fallback: Option<bool>,
-    #[serde(default)]
-    filters: Vec<ColorFilter>,
-    #[serde(flatten)]
-    source: ColorSource,
+    Ok(())
 }
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
-struct Color { r: u8, g: u8, b: u8, a: u8 }
+/// An input mask. Tall masks are only animations if they have a `.mcmeta`
+/// file, like textures in the game.
+struct Mask {
+    image: RgbaImage,
+    animated: bool,
+}
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum ColorSource {
-    Fill(Color),
-    Image(PathBuf),
+fn load_mask(path: &Path) -> layergen::Result<Mask> {
+    Ok(Mask { image: layergen::load_image(path)?, animated: mcmeta::path(path).is_file() })
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase", tag = "type")]
-enum ColorFilter {
-    Multiply {
-        color: Color,
+/// Records the mask at `path` and its `.mcmeta` file, if any, in `deps`.
+fn mask_inputs(path: &Path, deps: &mut Dependencies) {
+    deps.input(path);
+    let meta = mcmeta::path(path);
+    if meta.is_file() {
+        deps.input(meta);
     }
 }
 
-impl Color {
-    fn from_rgba(color: u32) -> Color {
-        let [a, r, g, b] = color.to_be_bytes();
-        Color { r, g, b, a }
+/// Generates one texture and its companion files, recording the files that
+/// were used in `deps`.
+fn run_job(output: &Path, map: &ColorMap, mask: &Mask, images: &ImageCache, png_options: &PngOptions, debug_map: bool, deps: &mut Dependencies) -> layergen::Result<()> {
+    for file in map.files.iter() {
+        deps.input(file);
     }
-
-    fn from_rgb(color: u32) -> Color {
-        Color::from_rgba(0xff000000 | color)
+    for file in map.images().into_iter().filter_map(|p| images.file(p)) {
+        deps.input(file);
     }
 
-    fn from_hex_string(s: &str) -> Result<Color, ()> {
-        if s.len() == 8 {
-            u32::from_str_radix(s, 16).map(Color::from_rgba).map_err(|_| ())
-        } else if s.len() == 6 {
-            u32::from_str_radix(s, 16).map(Color::from_rgb).map_err(|_| ())
-        } else {
-            Err(())
+    let companions = companion::needed(map);
+    let animated = map.animation.is_some() || mask.animated || layergen::output_frames(map, &mask.image, mask.animated, images) > 1;
+
+    if debug_map || companions {
+        let (output_image, stats, trace) = layergen::generate_with_trace(map, &mask.image, mask.animated, images)?;
+        report_stats(output, &stats);
+        deps.outputs.extend(save(output, &output_image, map, animated, png_options)?);
+
+        if debug_map {
+            let debug_image = output.with_extension("debug.png");
+            layergen::output::write(&debug_image, &trace.image(), png_options)?;
+            let report = output.with_extension("debug.txt");
+            fs::write(&report, trace.report(map)).map_err(|e| Error::Io(report.clone(), e))?;
+            deps.outputs.extend(vec![debug_image, report]);
         }
-    }
 
-    fn multiply(self, other: Color) -> Color {
-        Color {
-            r: dmul255(self.r, other.r),
-            g: dmul255(self.g, other.g),
-            b: dmul255(self.b, other.b),
-            a: dmul255(self.a, other.a),
+        if companions {
+            // companion maps go next to the texture, e.g. stone_n.png for stone.png
+            let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+            for (suffix, image) in companion::generate(map, &trace, &output_image).iter() {
+                let path = output.with_file_name(format!("{}{}.png", stem, suffix));
+                layergen::output::write(&path, image, png_options)?;
+                deps.outputs.push(path);
+            }
         }
+    } else {
+        let (output_image, stats) = layergen::generate_with_stats(map, &mask.image, mask.animated, images)?;
+        report_stats(output, &stats);
+        deps.outputs.extend(save(output, &output_image, map, animated, png_options)?);
     }
+
+    Ok(())
 }
 
-fn dmul255(a: u8, b: u8) -> u8 { (((a as f32 / 255.0) * (b as f32 / 255.0)) * 255.0) as u8 }
-
-impl<'de> Deserialize<'de> for Color {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where
-        D: Deserializer<'de> {
-        let s = String::deserialize(deserializer)?;
-        if s.starts_with("#") {
-            Color::from_hex_string(&s[1..]).map_err(|_| D::Error::custom("invalid hex syntax"))
-        } else {
-            match &*s {
-                "transparent" => Ok(Color::from_rgba(0x00000000)),
-                _ => Err(D::Error::custom("invalid color spec"))
-            }
+/// Generates connected texture tiles, either as one sheet or as separate
+/// images named by tile index in the `output` directory.
+fn run_ctm(spec_path: &Path, output: &Path, sheet: bool, png_options: &PngOptions, deps: &mut Dependencies) -> Result<(), Box<dyn std::error::Error>> {
+    deps.input(spec_path);
+
+    let file = File::open(spec_path).map_err(|e| Error::Io(spec_path.to_path_buf(), e))?;
+    let spec: CtmSpec = serde_yaml::from_reader(file).map_err(|e| format!("invalid connected texture {}: {}", spec_path.display(), e))?;
+
+    let base = spec_path.parent().unwrap_or_else(|| Path::new(""));
+    let paths = vec![Some(&spec.base), Some(&spec.border), Some(&spec.edge), spec.corner.as_ref(), spec.inner_corner.as_ref()];
+    for p in paths.into_iter().flatten() {
+        deps.input(base.join(p));
+    }
+
+    let load = |p: &Path| layergen::load_image(&base.join(p));
+    let images = CtmImages {
+        base: load(&spec.base)?,
+        border: load(&spec.border)?,
+        edge: load(&spec.edge)?,
+        corner: spec.corner.as_deref().map(load).transpose()?,
+        inner_corner: spec.inner_corner.as_deref().map(load).transpose()?,
+    };
+
+    let tiles = ctm::generate(spec.layout, &images)?;
+    if sheet {
+        layergen::output::write(output, &ctm::sheet(spec.layout, &tiles), png_options)?;
+        deps.outputs.push(output.to_path_buf());
+    } else {
+        fs::create_dir_all(output).map_err(|e| Error::Io(output.to_path_buf(), e))?;
+        for (i, tile) in tiles.iter().enumerate() {
+            let path = output.join(format!("{}.png", i));
+            layergen::output::write(&path, tile, png_options)?;
+            deps.outputs.push(path);
         }
     }
+
+    Ok(())
+}
+
+fn palette_swap(from: &Path, to: &Path) -> layergen::Result<ColorMap> {
+    let map = ColorMap::palette_swap(&Palette::load(from)?, &Palette::load(to)?)?;
+    Ok(ColorMap { files: vec![from.to_path_buf(), to.to_path_buf()], ..map })
+}
+
+/// Writes the texture and, if it is animated, its `.mcmeta` file, and returns
+/// the paths of the written files.
+fn save(output: &Path, image: &RgbaImage, map: &ColorMap, animated: bool, options: &PngOptions) -> layergen::Result<Vec<PathBuf>> {
+    layergen::output::write(output, image, options)?;
+    let mut files = vec![output.to_path_buf()];
+
+    if animated {
+        files.push(mcmeta::write(output, map.animation).map_err(|e| Error::Io(output.to_path_buf(), e))?);
+    }
+
+    Ok(files)
+}
+
+fn report_stats(output: &Path, stats: &MatchStats) {
+    if stats.fuzzy() > 0 {
+        eprintln!("{}: {} pixels matched by tolerance, {} by range, {} by nearest color",
+                  output.display(), stats.tolerance, stats.range, stats.nearest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{ImageBuffer, Rgba};
+
+    #[test]
+    fn tall_mask_writes_no_mcmeta() {
+        let dir = std::env::temp_dir().join("layergen-test-tall-mask");
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("out.png");
+        let _ = fs::remove_file(mcmeta::path(&output));
+
+        let map = ColorMap::from_yaml("- fallback: true\n  fill: '#ff0000'").unwrap();
+        let mask = Mask { image: ImageBuffer::from_pixel(2, 4, Rgba([0, 0, 0, 255])), animated: false };
+        let images = ImageCache::new(vec![]);
+        let mut deps = Dependencies::default();
+        run_job(&output, &map, &mask, &images, &PngOptions::default(), false, &mut deps).unwrap();
+
+        assert!(output.is_file());
+        assert!(!mcmeta::path(&output).exists());
+
+        let mask = Mask { animated: true, ..mask };
+        run_job(&output, &map, &mask, &images, &PngOptions::default(), false, &mut deps).unwrap();
+        assert!(mcmeta::path(&output).is_file());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,22 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Manifest {
+    #[serde(default)]
+    pub image_src: Vec<PathBuf>,
+    pub jobs: Vec<Job>,
+}
+
+/// A texture to generate, either from a color map or by swapping palettes.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Job {
+    pub image: PathBuf,
+    pub colormap: Option<PathBuf>,
+    pub palette_from: Option<PathBuf>,
+    pub palette_to: Option<PathBuf>,
+    pub output: PathBuf,
+}
\ No newline at end of file
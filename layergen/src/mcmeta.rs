@@ -0,0 +1,45 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::colormap::Animation;
+
+#[derive(Serialize)]
+struct McMeta {
+    animation: AnimationMeta,
+}
+
+#[derive(Serialize)]
+struct AnimationMeta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frametime: Option<u32>,
+    #[serde(skip_serializing_if = "is_false")]
+    interpolate: bool,
+}
+
+fn is_false(v: &bool) -> bool { !*v }
+
+/// The path of the `.mcmeta` file belonging to `texture`. Textures are only
+/// animated if they have one.
+pub fn path(texture: &Path) -> PathBuf {
+    let mut path = texture.as_os_str().to_owned();
+    path.push(".mcmeta");
+    PathBuf::from(path)
+}
+
+/// Writes the `.mcmeta` file for the animated texture at `texture`, and
+/// returns its path.
+pub fn write(texture: &Path, animation: Option<Animation>) -> io::Result<PathBuf> {
+    let meta = McMeta {
+        animation: AnimationMeta {
+            frametime: animation.and_then(|a| a.frametime),
+            interpolate: animation.is_some_and(|a| a.interpolate),
+        }
+    };
+
+    let path = path(texture);
+    serde_json::to_writer_pretty(File::create(&path)?, &meta)?;
+    Ok(path)
+}
\ No newline at end of file
@@ -0,0 +1,187 @@
+//! PNG encoding with control over the written color type and compression.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use image::RgbaImage;
+use png::{BitDepth, ColorType, Encoder, FilterType};
+
+use crate::error::{Error, Result};
+
+const FILTERS: [FilterType; 5] = [FilterType::NoFilter, FilterType::Sub, FilterType::Up, FilterType::Avg, FilterType::Paeth];
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum OutputColor {
+    /// Picks whichever of indexed or RGB(A) is smaller. RGB is used instead
+    /// of RGBA if the image is fully opaque.
+    #[default]
+    Auto,
+    Rgba,
+    /// Requires every pixel to be fully opaque.
+    Rgb,
+    /// Requires the image to have at most 256 distinct colors.
+    Indexed,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Compression {
+    Fast,
+    #[default]
+    Default,
+    /// Maximum compression, trying every filter type and keeping the
+    /// smallest result.
+    Best,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PngOptions {
+    pub color: OutputColor,
+    pub compression: Compression,
+}
+
+impl OutputColor {
+    pub fn from_name(name: &str) -> Option<OutputColor> {
+        match name {
+            "auto" => Some(OutputColor::Auto),
+            "rgba" => Some(OutputColor::Rgba),
+            "rgb" => Some(OutputColor::Rgb),
+            "indexed" => Some(OutputColor::Indexed),
+            _ => None,
+        }
+    }
+}
+
+impl Compression {
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "fast" => Some(Compression::Fast),
+            "default" => Some(Compression::Default),
+            "best" => Some(Compression::Best),
+            _ => None,
+        }
+    }
+}
+
+/// Raw image data in one of the PNG color types.
+struct Encoded {
+    color: ColorType,
+    depth: BitDepth,
+    palette: Option<(Vec<u8>, Vec<u8>)>,
+    data: Vec<u8>,
+}
+
+pub fn write(path: &Path, image: &RgbaImage, options: &PngOptions) -> Result<()> {
+    let (compression, filters): (_, &[FilterType]) = match options.compression {
+        Compression::Fast => (png::Compression::Fast, &[FilterType::Sub]),
+        Compression::Default => (png::Compression::Default, &[FilterType::Sub]),
+        Compression::Best => (png::Compression::Best, &FILTERS),
+    };
+
+    let mut best: Option<Vec<u8>> = None;
+    for encoded in candidates(path, image, options.color)?.iter() {
+        for filter in filters.iter() {
+            let data = encode(path, image, encoded, compression.clone(), *filter)?;
+            if best.as_ref().is_none_or(|b| data.len() < b.len()) {
+                best = Some(data);
+            }
+        }
+    }
+
+    fs::write(path, best.unwrap()).map_err(|e| Error::Io(path.to_path_buf(), e))
+}
+
+/// Converts the image to the formats worth trying for the requested color
+/// type. Palette images aren't always smaller than direct color, e.g. for
+/// smooth gradients, so automatic mode tries both.
+fn candidates(path: &Path, image: &RgbaImage, color: OutputColor) -> Result<Vec<Encoded>> {
+    let opaque = image.pixels().all(|p| p.0[3] == 0xFF);
+
+    match color {
+        OutputColor::Auto => {
+            let direct = if opaque { to_rgb(image) } else { to_rgba(image) };
+            Ok(to_indexed(image).into_iter().chain(Some(direct)).collect())
+        }
+        OutputColor::Rgba => Ok(vec![to_rgba(image)]),
+        OutputColor::Rgb if opaque => Ok(vec![to_rgb(image)]),
+        OutputColor::Rgb => Err(Error::Unrepresentable(path.to_path_buf(), "image has transparent pixels, can't write as RGB")),
+        OutputColor::Indexed => to_indexed(image)
+            .map(|e| vec![e])
+            .ok_or_else(|| Error::Unrepresentable(path.to_path_buf(), "image has more than 256 colors, can't write as indexed")),
+    }
+}
+
+fn to_rgba(image: &RgbaImage) -> Encoded {
+    Encoded { color: ColorType::RGBA, depth: BitDepth::Eight, palette: None, data: image.to_vec() }
+}
+
+fn to_rgb(image: &RgbaImage) -> Encoded {
+    let data = image.pixels().flat_map(|p| p.0[..3].to_vec()).collect();
+    Encoded { color: ColorType::RGB, depth: BitDepth::Eight, palette: None, data }
+}
+
+fn to_indexed(image: &RgbaImage) -> Option<Encoded> {
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut indices: HashMap<[u8; 4], u8> = HashMap::new();
+
+    for p in image.pixels() {
+        if let Entry::Vacant(e) = indices.entry(p.0) {
+            if palette.len() == 256 { return None; }
+            e.insert(palette.len() as u8);
+            palette.push(p.0);
+        }
+    }
+
+    let depth = match palette.len() {
+        0..=2 => BitDepth::One,
+        3..=4 => BitDepth::Two,
+        5..=16 => BitDepth::Four,
+        _ => BitDepth::Eight,
+    };
+    let bits = depth as usize;
+    let per_byte = 8 / bits;
+
+    // each row starts at a byte boundary
+    let mut data = Vec::new();
+    for row in image.rows() {
+        let row: Vec<u8> = row.map(|p| indices[&p.0]).collect();
+        for chunk in row.chunks(per_byte) {
+            let byte = chunk.iter().enumerate()
+                .fold(0u8, |acc, (i, idx)| acc | idx << (8 - bits * (i + 1)));
+            data.push(byte);
+        }
+    }
+
+    let rgb = palette.iter().flat_map(|c| c[..3].to_vec()).collect();
+
+    // tRNS only needs to cover entries up to the last non-opaque one
+    let trns_len = palette.iter().rposition(|c| c[3] != 0xFF).map_or(0, |i| i + 1);
+    let trns = palette[..trns_len].iter().map(|c| c[3]).collect();
+
+    Some(Encoded { color: ColorType::Indexed, depth, palette: Some((rgb, trns)), data })
+}
+
+fn encode(path: &Path, image: &RgbaImage, encoded: &Encoded, compression: png::Compression, filter: FilterType) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    {
+        let mut encoder = Encoder::new(&mut buf, image.width(), image.height());
+        encoder.set_color(encoded.color);
+        encoder.set_depth(encoded.depth);
+        encoder.set_compression(compression);
+        encoder.set_filter(filter);
+
+        if let Some((palette, trns)) = &encoded.palette {
+            encoder.set_palette(palette.clone());
+            if !trns.is_empty() {
+                encoder.set_trns(trns.clone());
+            }
+        }
+
+        let mut writer = encoder.write_header().map_err(|e| Error::Png(path.to_path_buf(), e))?;
+        writer.write_image_data(&encoded.data).map_err(|e| Error::Png(path.to_path_buf(), e))?;
+    }
+
+    Ok(buf)
+}
\ No newline at end of file
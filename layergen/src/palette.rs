@@ -0,0 +1,61 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::color::Color;
+use crate::error::{Error, Result};
+
+/// An ordered list of colors, e.g. the shades of a material from dark to
+/// bright.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "PaletteDef")]
+pub struct Palette {
+    pub name: Option<String>,
+    pub colors: Vec<Color>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PaletteDef {
+    Colors(Vec<Color>),
+    Named { name: Option<String>, colors: Vec<Color> },
+}
+
+impl From<PaletteDef> for Palette {
+    fn from(def: PaletteDef) -> Self {
+        match def {
+            PaletteDef::Colors(colors) => Palette { name: None, colors },
+            PaletteDef::Named { name, colors } => Palette { name, colors },
+        }
+    }
+}
+
+impl Palette {
+    /// Loads a palette from a YAML file, or from a file with one hex color
+    /// per line if it has the `.hex` extension.
+    pub fn load(path: &Path) -> Result<Palette> {
+        let s = fs::read_to_string(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+
+        let palette = if path.extension().is_some_and(|ext| ext == "hex") {
+            Palette::from_hex(&s).map_err(|msg| Error::InvalidPalette(path.to_path_buf(), msg))?
+        } else {
+            serde_yaml::from_str(&s).map_err(|e| Error::InvalidPalette(path.to_path_buf(), e.to_string()))?
+        };
+
+        if palette.colors.is_empty() {
+            return Err(Error::InvalidPalette(path.to_path_buf(), "palette has no colors".to_string()));
+        }
+
+        Ok(palette)
+    }
+
+    fn from_hex(s: &str) -> std::result::Result<Palette, String> {
+        let colors = s.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| Color::from_hex_string(l.trim_start_matches('#')).ok_or_else(|| format!("invalid color '{}'", l)))
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Palette { name: None, colors })
+    }
+}
\ No newline at end of file
@@ -0,0 +1,149 @@
+use std::convert::TryFrom;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::ident::Identifier;
+use crate::writer::write_identifier;
+
+const VERSION: u16 = 1;
+
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub id: Identifier,
+    pub offset: u32,
+    pub length: u32,
+}
+
+pub fn write<T: Write>(entries: &mut [(Identifier, Vec<u8>)], mut target: T) -> io::Result<()> {
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    if let Some(w) = entries.windows(2).find(|w| w[0].0 == w[1].0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Duplicate archive entry {}", w[0].0)));
+    }
+
+    // file header
+    write!(target, "MCBA")?;
+    target.write_u16::<LittleEndian>(VERSION)?;
+
+    target.write_u32::<LittleEndian>(to_u32(entries.len())?)?;
+
+    // index
+    let header_len = 4 + 2 + 4;
+    let index_len: usize = entries.iter().map(|(id, _)| identifier_len(id) + 4 + 4).sum();
+    let mut offset = header_len + index_len;
+    for (id, data) in entries.iter() {
+        // the end of the entry must be addressable too
+        to_u32(offset + data.len())?;
+        write_identifier(&mut target, id)?;
+        target.write_u32::<LittleEndian>(to_u32(offset)?)?;
+        target.write_u32::<LittleEndian>(to_u32(data.len())?)?;
+        offset += data.len();
+    }
+
+    // model data
+    for (_, data) in entries.iter() {
+        target.write_all(data)?;
+    }
+
+    Ok(())
+}
+
+pub fn read_index<T: Read>(mut source: T) -> io::Result<Vec<ArchiveEntry>> {
+    let mut magic = [0; 4];
+    source.read_exact(&mut magic)?;
+    if &magic != b"MCBA" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a model archive"));
+    }
+
+    let version = source.read_u16::<LittleEndian>()?;
+    if version != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported archive version {}", version)));
+    }
+
+    let len = source.read_u32::<LittleEndian>()?;
+    let mut entries = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        let id = read_identifier(&mut source)?;
+        let offset = source.read_u32::<LittleEndian>()?;
+        let length = source.read_u32::<LittleEndian>()?;
+        entries.push(ArchiveEntry { id, offset, length });
+    }
+
+    Ok(entries)
+}
+
+pub fn read_entry<T: Read + Seek>(mut source: T, entry: &ArchiveEntry) -> io::Result<Vec<u8>> {
+    source.seek(SeekFrom::Start(entry.offset as u64))?;
+    let mut buf = vec![0; entry.length as usize];
+    source.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn to_u32(value: usize) -> io::Result<u32> {
+    u32::try_from(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Archive is too large"))
+}
+
+fn identifier_len(identifier: &Identifier) -> usize {
+    let namespace_len = if identifier.namespace == "minecraft" { 0 } else { identifier.namespace.len() };
+    2 + 2 + namespace_len + identifier.path.len()
+}
+
+pub fn read_identifier<T: Read>(mut source: T) -> io::Result<Identifier> {
+    let namespace_len = source.read_u16::<LittleEndian>()?;
+    let path_len = source.read_u16::<LittleEndian>()?;
+    let namespace = if namespace_len == u16::MAX { "minecraft".to_string() } else { read_string(&mut source, namespace_len as usize)? };
+    let path = read_string(&mut source, path_len as usize)?;
+    Ok(Identifier::new(namespace, path))
+}
+
+pub fn read_string<T: Read>(mut source: T, len: usize) -> io::Result<String> {
+    let mut buf = vec![0; len];
+    source.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut entries = vec![
+            (Identifier::new("foo", "block/b"), vec![4, 5]),
+            (Identifier::new("minecraft", "item/bow"), vec![]),
+            (Identifier::new("foo", "block/a"), vec![1, 2, 3]),
+        ];
+        let mut buf = Vec::new();
+        write(&mut entries, &mut buf).unwrap();
+
+        let mut source = Cursor::new(buf);
+        let index = read_index(&mut source).unwrap();
+        let ids: Vec<_> = index.iter().map(|e| e.id.to_string()).collect();
+        assert_eq!(ids, ["foo:block/a", "foo:block/b", "minecraft:item/bow"]);
+
+        let data: Vec<_> = index.iter().map(|e| read_entry(&mut source, e).unwrap()).collect();
+        assert_eq!(data, [vec![1, 2, 3], vec![4, 5], vec![]]);
+    }
+
+    #[test]
+    fn duplicate_entries() {
+        let mut entries = vec![(Identifier::new("foo", "a"), vec![1]), (Identifier::new("foo", "a"), vec![2])];
+        assert!(write(&mut entries, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        let e = to_u32(u32::MAX as usize + 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(to_u32(u32::MAX as usize).unwrap(), u32::MAX);
+    }
+
+    #[test]
+    fn wrong_magic() {
+        assert!(read_index(Cursor::new(b"MCBM\x01\x00\x00\x00\x00\x00".to_vec())).is_err());
+    }
+}
\ No newline at end of file
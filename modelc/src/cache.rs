@@ -0,0 +1,266 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::archive::{read_identifier, read_string};
+use crate::ident::Identifier;
+use crate::writer;
+use crate::writer::write_identifier;
+
+/// How old a temporary file must be to be considered left behind by an
+/// interrupted build rather than belonging to one that is still running.
+const STALE_TMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A compiled model together with the files it was compiled from.
+pub struct Entry {
+    pub data: Vec<u8>,
+    /// The input file, followed by every parent and override model that was
+    /// read.
+    pub dependencies: Vec<PathBuf>,
+    /// The textures used by the model and its overrides.
+    pub textures: Vec<Identifier>,
+}
+
+/// A directory of compiled models.
+///
+/// Entries are looked up by a key derived from the input file path and
+/// contents, the compiler and output format version, the model type and the
+/// include roots. Since the parent models of an input are only known after
+/// parsing it, each entry also stores the content hashes of all models it
+/// depends on, and is only used if none of them have changed and none of
+/// them are shadowed by a new file in an earlier include root.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let cache = Cache { dir: dir.to_path_buf() };
+        cache.clean()?;
+        Ok(cache)
+    }
+
+    /// Removes entries of other format versions, which are never used, and
+    /// temporary files left behind by interrupted builds.
+    fn clean(&self) -> io::Result<()> {
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            let remove = match path.extension().and_then(|e| e.to_str()) {
+                Some("mcbc") => !is_current(&path),
+                Some("tmp") => is_stale(&path),
+                _ => false,
+            };
+            if remove {
+                // another build may have removed it first
+                let _ = fs::remove_file(&path);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn key(file: &Path, typ: &str, include: &[&Path]) -> io::Result<u64> {
+        let mut hash = Fnv::new();
+        hash.write(env!("CARGO_PKG_VERSION").as_bytes());
+        hash.write(&writer::VERSION.to_le_bytes());
+        hash.write(typ.as_bytes());
+        hash.write(file.to_string_lossy().as_bytes());
+        hash.write(&[0]);
+        for root in include.iter() {
+            hash.write(root.to_string_lossy().as_bytes());
+            hash.write(&[0]);
+        }
+        hash.write(&fs::read(file)?);
+        Ok(hash.finish())
+    }
+
+    /// Returns the entry for `key` if there is one and it is still valid.
+    /// `file` is the input file, which the entry must have been created for.
+    pub fn get(&self, key: u64, file: &Path, include: &[&Path]) -> Option<Entry> {
+        let source = File::open(self.path(key)).ok()?;
+        let (entry, hashes) = read_entry(BufReader::new(source)).ok()?;
+        if entry.dependencies.first().map(PathBuf::as_path) != Some(file) { return None; }
+
+        for (i, (path, &hash)) in entry.dependencies.iter().zip(hashes.iter()).enumerate() {
+            if fs::read(path).ok().map(|d| hash_bytes(&d)) != Some(hash) { return None; }
+            if i > 0 && is_shadowed(path, include) { return None; }
+        }
+
+        Some(entry)
+    }
+
+    pub fn put(&self, key: u64, entry: &Entry) -> io::Result<()> {
+        let hashes = entry.dependencies.iter().map(|p| fs::read(p).map(|d| hash_bytes(&d))).collect::<io::Result<Vec<_>>>()?;
+
+        // write to a temporary file first so that an interrupted build
+        // doesn't leave a broken entry behind, named by process so that
+        // parallel builds don't write to the same one
+        let path = self.path(key);
+        let tmp = self.dir.join(format!("{:016x}.{}.tmp", key, process::id()));
+        let mut target = BufWriter::new(File::create(&tmp)?);
+        write_entry(&mut target, entry, &hashes)?;
+        target.flush()?;
+        drop(target);
+        fs::rename(tmp, path)
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.mcbc", key))
+    }
+}
+
+/// Whether a model file found in one include root would now be found in an
+/// earlier one instead.
+fn is_shadowed(path: &Path, include: &[&Path]) -> bool {
+    let idx = match include.iter().position(|root| path.starts_with(root)) {
+        None => return false,
+        Some(idx) => idx,
+    };
+    let relative = path.strip_prefix(include[idx]).unwrap();
+    include[..idx].iter().any(|root| root.join(relative).exists())
+}
+
+/// Whether the cache entry at `path` has the current format version.
+fn is_current(path: &Path) -> bool {
+    File::open(path).and_then(|mut f| read_header(&mut f)).is_ok()
+}
+
+fn is_stale(path: &Path) -> bool {
+    let age = fs::metadata(path).and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+    age.is_some_and(|age| age > STALE_TMP_AGE)
+}
+
+fn write_entry<T: Write>(mut target: T, entry: &Entry, hashes: &[u64]) -> io::Result<()> {
+    // file header
+    write!(target, "MCBC")?;
+    target.write_u16::<LittleEndian>(writer::VERSION)?;
+
+    target.write_u32::<LittleEndian>(entry.dependencies.len() as u32)?;
+    for (path, &hash) in entry.dependencies.iter().zip(hashes.iter()) {
+        let path = path.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path is not valid UTF-8"))?;
+        target.write_u32::<LittleEndian>(path.len() as u32)?;
+        target.write_all(path.as_bytes())?;
+        target.write_u64::<LittleEndian>(hash)?;
+    }
+
+    target.write_u32::<LittleEndian>(entry.textures.len() as u32)?;
+    for id in entry.textures.iter() {
+        write_identifier(&mut target, id)?;
+    }
+
+    target.write_all(&entry.data)
+}
+
+fn read_header<T: Read>(source: &mut T) -> io::Result<()> {
+    let mut magic = [0; 4];
+    source.read_exact(&mut magic)?;
+    if &magic != b"MCBC" || source.read_u16::<LittleEndian>()? != writer::VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Outdated cache entry"));
+    }
+    Ok(())
+}
+
+fn read_entry<T: Read>(mut source: T) -> io::Result<(Entry, Vec<u64>)> {
+    read_header(&mut source)?;
+
+    let len = source.read_u32::<LittleEndian>()?;
+    let mut dependencies = Vec::new();
+    let mut hashes = Vec::new();
+    for _ in 0..len {
+        let path_len = source.read_u32::<LittleEndian>()?;
+        dependencies.push(PathBuf::from(read_string(&mut source, path_len as usize)?));
+        hashes.push(source.read_u64::<LittleEndian>()?);
+    }
+
+    let len = source.read_u32::<LittleEndian>()?;
+    let textures = (0..len).map(|_| read_identifier(&mut source)).collect::<io::Result<_>>()?;
+
+    let mut data = Vec::new();
+    source.read_to_end(&mut data)?;
+
+    Ok((Entry { data, dependencies, textures }, hashes))
+}
+
+fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hash = Fnv::new();
+    hash.write(data);
+    hash.finish()
+}
+
+/// 64 bit FNV-1a, which unlike the standard library hasher is guaranteed to
+/// stay the same between Rust versions.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self { Fnv(0xcbf29ce484222325) }
+
+    fn write(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 { self.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_round_trip() {
+        let entry = Entry {
+            data: vec![1, 2, 3],
+            dependencies: vec![PathBuf::from("assets/foo/models/block/a.json"), PathBuf::from("assets/minecraft/models/block/cube.json")],
+            textures: vec![Identifier::new("foo", "block/a"), Identifier::new("minecraft", "block/stone")],
+        };
+        let mut buf = Vec::new();
+        write_entry(&mut buf, &entry, &[1, u64::MAX]).unwrap();
+
+        let (read, hashes) = read_entry(&buf[..]).unwrap();
+        assert_eq!(read.data, entry.data);
+        assert_eq!(read.dependencies, entry.dependencies);
+        assert_eq!(read.textures, entry.textures);
+        assert_eq!(hashes, [1, u64::MAX]);
+    }
+
+    #[test]
+    fn outdated_entry() {
+        let mut buf = b"MCBC".to_vec();
+        buf.extend_from_slice(&(writer::VERSION - 1).to_le_bytes());
+        buf.extend_from_slice(&[0; 8]);
+        assert!(read_entry(&buf[..]).is_err());
+    }
+
+    #[test]
+    fn clean_outdated_files() {
+        let dir = std::env::temp_dir().join("modelc-test-cache-clean");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut current = Vec::new();
+        write_entry(&mut current, &Entry { data: vec![], dependencies: vec![], textures: vec![] }, &[]).unwrap();
+        let mut outdated = b"MCBC".to_vec();
+        outdated.extend_from_slice(&(writer::VERSION - 1).to_le_bytes());
+
+        fs::write(dir.join("current.mcbc"), &current).unwrap();
+        fs::write(dir.join("outdated.mcbc"), &outdated).unwrap();
+        fs::write(dir.join("broken.mcbc"), b"MC").unwrap();
+        fs::write(dir.join("running.tmp"), &current).unwrap();
+        fs::write(dir.join("interrupted.tmp"), &current).unwrap();
+        File::options().write(true).open(dir.join("interrupted.tmp")).unwrap()
+            .set_modified(std::time::SystemTime::now() - 2 * STALE_TMP_AGE).unwrap();
+
+        Cache::new(&dir).unwrap();
+
+        let mut left: Vec<_> = fs::read_dir(&dir).unwrap().map(|f| f.unwrap().file_name()).collect();
+        left.sort();
+        assert_eq!(left, ["current.mcbc", "running.tmp"]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,31 @@
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Writes Makefile rules making each output depend on the listed files.
+pub fn write<T: Write>(rules: &[(PathBuf, Vec<PathBuf>)], mut target: T) -> io::Result<()> {
+    for (output, inputs) in rules.iter() {
+        write!(target, "{}:", escape(output))?;
+        for input in inputs.iter() {
+            write!(target, " \\\n  {}", escape(input))?;
+        }
+        writeln!(target)?;
+    }
+
+    Ok(())
+}
+
+fn escape(path: &Path) -> String {
+    let mut s = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' | '\\' => {
+                s.push('\\');
+                s.push(c);
+            }
+            '$' => s.push_str("$$"),
+            _ => s.push(c),
+        }
+    }
+    s
+}
\ No newline at end of file
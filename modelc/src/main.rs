@@ -1,10 +1,12 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
 use clap::{app_from_crate, Arg};
 
+use crate::cache::{Cache, Entry};
 use crate::ident::Identifier;
 
 mod ident;
@@ -13,6 +15,9 @@ mod types;
 mod vanilla;
 mod model;
 mod writer;
+mod archive;
+mod cache;
+mod depfile;
 
 fn main() {
     let matches = app_from_crate!()
@@ -20,20 +25,126 @@ fn main() {
         .arg(Arg::with_name("output").short('o').long("output").value_name("PATH"))
         .arg(Arg::with_name("identifiers").short('i').long("identifiers"))
         .arg(Arg::with_name("type").short('t').long("type").default_value("auto").possible_values(&["auto", "blockstate", "model"]))
+        .arg(Arg::with_name("archive").short('a').long("archive").conflicts_with("extract"))
+        .arg(Arg::with_name("extract").short('x').long("extract").value_name("ID"))
         .arg(Arg::with_name("debug").short('d').long("debug").multiple_occurrences(true))
-        .arg(Arg::with_name("file").required(true))
+        .arg(Arg::with_name("depfile").short('M').long("depfile").value_name("PATH"))
+        .arg(Arg::with_name("depfile-textures").long("depfile-textures").requires("depfile"))
+        .arg(Arg::with_name("cache").long("cache").value_name("DIR"))
+        .arg(Arg::with_name("file").required(true).multiple(true))
         .get_matches();
 
     let include = matches.values_of_os("include").map_or_else(Vec::new, |iter| iter.map(Path::new).collect());
     let output = matches.value_of_os("output").map(Path::new);
     let identifiers = matches.is_present("identifiers");
     let typ = matches.value_of("type").unwrap();
+    let archive = matches.is_present("archive");
+    let extract = matches.value_of("extract");
     let debug = matches.occurrences_of("debug");
-    let file = Path::new(matches.value_of_os("file").unwrap());
+    let depfile = matches.value_of_os("depfile").map(Path::new);
+    let depfile_textures = matches.is_present("depfile-textures");
+    let cache = matches.value_of_os("cache").map(|dir| Cache::new(Path::new(dir)).expect("Failed to create cache directory"));
+    let files: Vec<_> = matches.values_of_os("file").unwrap().map(Path::new).collect();
 
-    let output = output.map(Cow::Borrowed).unwrap_or_else(|| file.file_name().map_or_else(|| "a.bin".into(), |s| Path::new(s).with_extension("bin")).into());
+    // (output, files it was built from) for the dependency file
+    let mut rules: Vec<(PathBuf, Vec<PathBuf>)> = Vec::new();
 
-    let mut file = File::open(file).expect("Failed to open input file");
+    if let Some(id) = extract {
+        let id: Identifier = id.parse().expect("Invalid identifier");
+        if files.len() != 1 { panic!("Exactly one archive must be specified for extraction"); }
+
+        let mut file = File::open(files[0]).expect("Failed to open archive");
+        let index = archive::read_index(&mut file).expect("Failed to read archive index");
+        let entry = index.iter().find(|e| e.id == id).unwrap_or_else(|| panic!("Archive does not contain {}", id));
+        let data = archive::read_entry(&mut file, entry).expect("Failed to read archive entry");
+
+        let output = output.map(Cow::Borrowed).unwrap_or_else(|| Path::new(id.path.rsplit('/').next().unwrap()).with_extension("bin").into());
+        std::fs::write(&output, data).expect("Failed to write output file");
+        rules.push((output.into_owned(), vec![files[0].to_path_buf()]));
+    } else if archive {
+        let mut entries = Vec::new();
+        let mut deps = Vec::new();
+        for &file in files.iter() {
+            let id = model_path_to_identifier(file).unwrap_or_else(|| panic!("Can't determine model identifier for {}", file.display()));
+            let entry = build(file, typ, &include, cache.as_ref());
+
+            for path in entry.dependencies {
+                add_dependency(&mut deps, path);
+            }
+            if depfile_textures { texture_dependencies(&entry.textures, &include, &mut deps); }
+            entries.push((id, entry.data));
+        }
+
+        let output = output.unwrap_or_else(|| Path::new("a.mcba"));
+        archive::write(&mut entries, &mut File::create(output).unwrap()).unwrap();
+        rules.push((output.to_path_buf(), deps));
+    } else if files.len() == 1 {
+        let file = files[0];
+        let output = output.map(Cow::Borrowed).unwrap_or_else(|| file.file_name().map_or_else(|| "a.bin".into(), |s| Path::new(s).with_extension("bin")).into());
+        let mut entry = build(file, typ, &include, cache.as_ref());
+        std::fs::write(&output, &entry.data).expect("Failed to write output file");
+
+        if depfile_textures { texture_dependencies(&entry.textures, &include, &mut entry.dependencies); }
+        rules.push((output.into_owned(), entry.dependencies));
+    } else {
+        // batch mode without archive, output is a directory
+        let output_dir = output.unwrap_or_else(|| Path::new("."));
+        let outputs: Vec<_> = files.iter()
+            .map(|file| output_dir.join(Path::new(file.file_name().expect("Input file has no name")).with_extension("bin")))
+            .collect();
+
+        // check before writing anything, so that no output silently replaces
+        // another
+        let mut seen = HashMap::new();
+        for (&file, output) in files.iter().zip(outputs.iter()) {
+            if let Some(other) = seen.insert(output, file) {
+                panic!("Both {} and {} would be compiled to {}", other.display(), file.display(), output.display());
+            }
+        }
+
+        for (&file, output) in files.iter().zip(outputs) {
+            let mut entry = build(file, typ, &include, cache.as_ref());
+            std::fs::write(&output, &entry.data).expect("Failed to write output file");
+
+            if depfile_textures { texture_dependencies(&entry.textures, &include, &mut entry.dependencies); }
+            rules.push((output, entry.dependencies));
+        }
+    }
+
+    if let Some(depfile) = depfile {
+        depfile::write(&rules, File::create(depfile).expect("Failed to create dependency file")).expect("Failed to write dependency file");
+    }
+}
+
+/// Compiles the model in `file` to its binary form, or takes it from the
+/// cache if neither it nor any of its parents changed since it was cached.
+fn build(file: &Path, typ: &str, include: &[&Path], cache: Option<&Cache>) -> Entry {
+    let key = cache.map(|_| Cache::key(file, typ, include).expect("Failed to read input file"));
+    if let (Some(cache), Some(key)) = (cache, key) {
+        if let Some(entry) = cache.get(key, file, include) {
+            return entry;
+        }
+    }
+
+    let (model, dependencies) = compile(file, typ, include);
+    let mut data = Vec::new();
+    writer::write(&model, &mut data).unwrap();
+    let mut textures = Vec::new();
+    model_textures(&model, &mut textures);
+    let entry = Entry { data, dependencies, textures };
+
+    if let (Some(cache), Some(key)) = (cache, key) {
+        cache.put(key, &entry).expect("Failed to write cache entry");
+    }
+
+    entry
+}
+
+/// Compiles the model in `file`, and returns it together with the paths of
+/// all model files that were read for it.
+fn compile(path: &Path, typ: &str, include: &[&Path]) -> (model::Model, Vec<PathBuf>) {
+    let mut deps = vec![path.to_path_buf()];
+    let mut file = File::open(path).expect("Failed to open input file");
 
     let mut model = match typ {
         "auto" => {
@@ -60,11 +171,10 @@ fn main() {
         _ => unreachable!()
     };
 
-    model.resolve(&include);
-
-    let model = model.to_model();
+    model.resolve(include, &mut deps);
 
-    writer::write(&model, &mut File::create(output).unwrap()).unwrap();
+    let model = model.to_model(model_path_to_identifier(path), include, &mut deps);
+    (model, deps)
 }
 
 fn identifier_to_model_path(id: &Identifier) -> PathBuf {
@@ -73,41 +183,203 @@ fn identifier_to_model_path(id: &Identifier) -> PathBuf {
     Path::new(&id.namespace).join("models").join(string)
 }
 
+fn identifier_to_texture_path(id: &Identifier) -> PathBuf {
+    let mut string = id.path.to_string();
+    string.push_str(".png");
+    Path::new(&id.namespace).join("textures").join(string)
+}
+
+fn model_path_to_identifier(path: &Path) -> Option<Identifier> {
+    let path = path.with_extension("");
+    let components: Vec<_> = path.iter().map(|c| c.to_str()).collect::<Option<_>>()?;
+    let idx = components.iter().rposition(|&c| c == "models")?;
+    if idx == 0 || idx + 1 == components.len() { return None; }
+    format!("{}:{}", components[idx - 1], components[idx + 1..].join("/")).parse().ok()
+}
+
+fn add_dependency(deps: &mut Vec<PathBuf>, path: PathBuf) {
+    if !deps.contains(&path) {
+        deps.push(path);
+    }
+}
+
+/// Collects the textures used by the model and its overrides.
+fn model_textures(model: &model::Model, textures: &mut Vec<Identifier>) {
+    let ids = std::iter::once(&model.particle)
+        .chain(model.meshes.iter().flat_map(|m| m.quads.iter()).map(|q| &q.texture));
+
+    for id in ids {
+        if !textures.contains(id) {
+            textures.push(id.clone());
+        }
+    }
+
+    for o in model.overrides.iter() {
+        if let model::OverrideModel::Embedded(m) = &o.model {
+            model_textures(m, textures);
+        }
+    }
+}
+
+/// Adds the texture files for the textures. Textures that aren't found in
+/// any include root are left out.
+fn texture_dependencies(textures: &[Identifier], include: &[&Path], deps: &mut Vec<PathBuf>) {
+    for id in textures.iter() {
+        let texture_path = identifier_to_texture_path(id);
+        if let Some(path) = include.iter().map(|root| root.join(&texture_path)).find(|p| p.is_file()) {
+            add_dependency(deps, path);
+        }
+    }
+}
+
+fn find_model(id: &Identifier, include: &[&Path], deps: &mut Vec<PathBuf>) -> Option<vanilla::model::Model> {
+    let model_path = identifier_to_model_path(id);
+    for &root in include.iter() {
+        let path = root.join(&model_path);
+        if let Ok(file) = File::open(&path) {
+            add_dependency(deps, path);
+            return Some(serde_json::from_reader(file).unwrap_or_else(|e| panic!("Failed to parse model {}: {}", id, e)));
+        }
+    }
+    None
+}
+
+fn resolve_parents(model: &mut vanilla::model::Model, include: &[&Path], deps: &mut Vec<PathBuf>) {
+    while let Some(parent_id) = &model.parent {
+        match find_model(parent_id, include, deps) {
+            None => panic!("Could not find referenced parent model {}", parent_id),
+            Some(m) => {
+                model.merge(m);
+            }
+        }
+    }
+}
+
+fn compile_overrides(model: &vanilla::model::Model, include: &[&Path], stack: &mut Vec<Identifier>, deps: &mut Vec<PathBuf>) -> Vec<model::Override> {
+    model.overrides.iter().map(|o| {
+        let predicate = o.predicate.iter().map(|(k, &v)| (k.clone(), v)).collect();
+
+        // models like the vanilla compass list themselves as an override,
+        // which can only be compiled as a reference
+        if let Some(pos) = stack.iter().rposition(|id| *id == o.model) {
+            return model::Override { predicate, model: model::OverrideModel::Enclosing((stack.len() - 1 - pos) as u16) };
+        }
+
+        let mut target = find_model(&o.model, include, deps).unwrap_or_else(|| panic!("Could not find referenced override model {}", o.model));
+        resolve_parents(&mut target, include, deps);
+
+        stack.push(o.model.clone());
+        let mut compiled = model::Model::from_json_model(&target).unwrap();
+        compiled.overrides = compile_overrides(&target, include, stack, deps);
+        stack.pop();
+
+        model::Override { predicate, model: model::OverrideModel::Embedded(Box::new(compiled)) }
+    }).collect()
+}
+
 enum AnyModel {
     Model(vanilla::model::Model),
     BlockState(vanilla::blockstate::BlockStateDef),
 }
 
 impl AnyModel {
-    pub fn resolve(&mut self, include: &[&Path]) {
+    pub fn resolve(&mut self, include: &[&Path], deps: &mut Vec<PathBuf>) {
         match self {
-            AnyModel::Model(model) => {
-                while let Some(parent_id) = &model.parent {
-                    let model_path = identifier_to_model_path(parent_id);
-                    let mut parent = None;
-                    for &root in include.iter() {
-                        let model_path = root.join(&model_path);
-                        if let Ok(file) = File::open(model_path) {
-                            parent = Some(serde_json::from_reader(file).expect("Failed to parse parent model"));
-                            break;
-                        }
-                    }
-                    match parent {
-                        None => panic!("Could not find referenced parent model {}", parent_id),
-                        Some(m) => {
-                            model.merge(m);
-                        }
-                    }
-                }
-            },
+            AnyModel::Model(model) => resolve_parents(model, include, deps),
             AnyModel::BlockState(state) => unimplemented!(),
         }
     }
 
-    pub fn to_model(&self) -> model::Model {
+    /// Compiles the resolved model. `id` is the identifier of the model
+    /// itself if known, so that overrides referring back to it are detected.
+    pub fn to_model(&self, id: Option<Identifier>, include: &[&Path], deps: &mut Vec<PathBuf>) -> model::Model {
         match self {
-            AnyModel::Model(model) => model::Model::from_json_model(&model).unwrap(),
+            AnyModel::Model(model) => {
+                let mut compiled = model::Model::from_json_model(&model).unwrap();
+                compiled.overrides = compile_overrides(model, include, &mut id.into_iter().collect(), deps);
+                compiled
+            }
             AnyModel::BlockState(state) => unimplemented!(),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Creates a fresh directory in the system temp directory containing the
+    /// given files.
+    fn fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("modelc-test-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, content) in files.iter() {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn cache_identical_files() {
+        let model = r#"{"parent":"block/cube","textures":{"all":"block/stone"}}"#;
+        let dir = fixture("cache-identical", &[
+            ("assets/minecraft/models/block/cube.json", "{}"),
+            ("assets/foo/models/item/a.json", model),
+            ("assets/foo/models/item/b.json", model),
+        ]);
+        let assets = dir.join("assets");
+        let cache = Cache::new(&dir.join("cache")).unwrap();
+        let a = assets.join("foo/models/item/a.json");
+        let b = assets.join("foo/models/item/b.json");
+        let cube = assets.join("minecraft/models/block/cube.json");
+
+        // the second round comes from the cache
+        for _ in 0..2 {
+            let mut rules = Vec::new();
+            for file in [&a, &b].iter() {
+                let entry = build(file, "model", &[&assets], Some(&cache));
+                rules.push((file.with_extension("bin"), entry.dependencies));
+            }
+            assert_eq!(rules[0].1, [a.clone(), cube.clone()]);
+            assert_eq!(rules[1].1, [b.clone(), cube.clone()]);
+
+            let mut depfile = Vec::new();
+            depfile::write(&rules[1..], &mut depfile).unwrap();
+            let depfile = String::from_utf8(depfile).unwrap();
+            assert!(depfile.contains("b.json") && !depfile.contains("a.json"));
+        }
+
+        // editing one file doesn't invalidate the other
+        fs::write(&a, r#"{"parent":"block/cube","textures":{"all":"block/dirt"}}"#).unwrap();
+        let key = Cache::key(&b, "model", &[&assets]).unwrap();
+        assert!(cache.get(key, &b, &[&assets]).is_some());
+    }
+
+    #[test]
+    fn override_cycle() {
+        let dir = fixture("override-cycle", &[
+            ("assets/minecraft/models/item/compass.json", r#"{"overrides":[{"predicate":{"angle":0.0},"model":"item/compass"},{"predicate":{"angle":0.5},"model":"item/compass_16"}]}"#),
+            ("assets/minecraft/models/item/compass_16.json", r#"{}"#),
+            ("assets/foo/models/item/a.json", r#"{"overrides":[{"predicate":{"pull":1},"model":"foo:item/b"}]}"#),
+            ("assets/foo/models/item/b.json", r#"{"overrides":[{"predicate":{"pull":1},"model":"foo:item/a"}]}"#),
+        ]);
+        let assets = dir.join("assets");
+
+        // a model that lists itself refers to itself
+        let (compass, _) = compile(&assets.join("minecraft/models/item/compass.json"), "model", &[&assets]);
+        assert!(matches!(compass.overrides[0].model, model::OverrideModel::Enclosing(0)));
+        assert!(matches!(compass.overrides[1].model, model::OverrideModel::Embedded(_)));
+        writer::write(&compass, Vec::new()).unwrap();
+
+        // a -> b -> a refers from b back to a
+        let (a, _) = compile(&assets.join("foo/models/item/a.json"), "model", &[&assets]);
+        match &a.overrides[0].model {
+            model::OverrideModel::Embedded(b) => assert!(matches!(b.overrides[0].model, model::OverrideModel::Enclosing(1))),
+            m => panic!("expected embedded model, got {:?}", m),
+        }
+    }
 }
\ No newline at end of file
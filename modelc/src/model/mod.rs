@@ -1,5 +1,5 @@
 use crate::ident::Identifier;
-use crate::types::{Direction, Display, Vec2, Vec3};
+use crate::types::{BlendMode, Direction, Display, GuiLight, Vec2, Vec3};
 use crate::vanilla::BlockStateDef;
 use crate::vanilla::Model as JsonModel;
 
@@ -9,7 +9,24 @@ mod quadifier;
 pub struct Model {
    pub particle: Identifier,
    pub transformation: Display,
+   pub gui_light: GuiLight,
+   pub render_type: Option<Identifier>,
    pub meshes: Vec<Mesh>,
+   pub overrides: Vec<Override>,
+}
+
+#[derive(Debug)]
+pub struct Override {
+    pub predicate: Vec<(Identifier, f32)>,
+    pub model: OverrideModel,
+}
+
+#[derive(Debug)]
+pub enum OverrideModel {
+    Embedded(Box<Model>),
+    /// Refers to a model this override is nested in, counting outwards from
+    /// the model the override belongs to (0).
+    Enclosing(u16),
 }
 
 #[derive(Debug)]
@@ -24,6 +41,15 @@ pub struct Quad {
     pub normal: Vec3,
     pub color_index: i32,
     pub cull_face: Option<Direction>,
+    pub material: Material,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Material {
+    pub blend_mode: BlendMode,
+    pub emissive: bool,
+    pub disable_ao: bool,
+    pub disable_diffuse: bool,
 }
 
 #[derive(Debug)]
@@ -42,11 +68,14 @@ impl Model {
 
         let tex = model.texture("particle").unwrap_or(Identifier::new("minecraft", "missingno"));
         let tr = model.display.clone().into();
-        let mesh = quadifier::cubes_to_mesh(model.elements(), &model.textures);
+        let mesh = quadifier::cubes_to_mesh(model.elements(), &model.textures, model.material());
         Ok(Model {
             particle: tex,
             transformation: tr,
+            gui_light: model.gui_light(),
+            render_type: model.render_type.clone(),
             meshes: vec![mesh],
+            overrides: vec![],
         })
     }
 }
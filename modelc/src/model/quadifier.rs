@@ -1,14 +1,22 @@
 use std::collections::HashMap;
 
-use crate::model::{Mesh, Quad, Vertex};
+use crate::model::{Material, Mesh, Quad, Vertex};
 use crate::types::{Direction, Vec2, Vec3};
-use crate::vanilla::model::{Cube, TextureRef};
+use crate::vanilla::model::{Cube, Material as JsonMaterial, TextureRef};
 
-pub fn cubes_to_mesh(cubes: &[Cube], textures: &HashMap<String, TextureRef>) -> Mesh {
+pub fn cubes_to_mesh(cubes: &[Cube], textures: &HashMap<String, TextureRef>, material: JsonMaterial) -> Mesh {
     let mut quads = vec![];
 
     for cube in cubes {
         for (d, face) in cube.faces.iter() {
+            let m = face.material().or(cube.material).or(material);
+            let material = Material {
+                blend_mode: m.blend_mode.unwrap_or_default(),
+                emissive: m.emissive.unwrap_or(false),
+                disable_ao: m.disable_ao.unwrap_or(false),
+                disable_diffuse: m.disable_diffuse.unwrap_or(!cube.shade),
+            };
+
             let uvs = face.uv.unwrap_or_else(|| {
                 let a = match d {
                     Direction::Down | Direction::Up => [cube.from[0], cube.from[2], cube.to[0], cube.to[2]],
@@ -117,6 +125,7 @@ pub fn cubes_to_mesh(cubes: &[Cube], textures: &HashMap<String, TextureRef>) ->
                 normal: d.vector(),
                 color_index: face.tintindex(),
                 cull_face: face.cullface,
+                material,
             })
         }
     }
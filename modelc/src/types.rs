@@ -45,6 +45,35 @@ impl Direction {
     pub const fn negative_axis(self) -> bool { self.index() % 2 == 0 }
 }
 
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode { #[default] Default, Solid, CutoutMipped, Cutout, Translucent }
+
+impl BlendMode {
+    pub const fn index(self) -> usize {
+        match self {
+            BlendMode::Default => 0,
+            BlendMode::Solid => 1,
+            BlendMode::CutoutMipped => 2,
+            BlendMode::Cutout => 3,
+            BlendMode::Translucent => 4,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GuiLight { #[default] Side, Front }
+
+impl GuiLight {
+    pub const fn index(self) -> usize {
+        match self {
+            GuiLight::Side => 0,
+            GuiLight::Front => 1,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Default)]
 #[serde(default)]
 pub struct Display {
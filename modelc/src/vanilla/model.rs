@@ -1,36 +1,52 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use serde::{Deserialize, Deserializer};
 use serde::de::Error;
 
 use crate::ident::Identifier;
-use crate::types::{Direction, DisplayTransformation, Vec3};
+use crate::types::{BlendMode, Direction, DisplayTransformation, GuiLight, Vec3};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Model {
     pub parent: Option<Identifier>,
     pub ambientocclusion: Option<bool>,
+    pub gui_light: Option<GuiLight>,
+    pub render_type: Option<Identifier>,
     #[serde(default)]
     pub display: Display,
     #[serde(default)]
     pub textures: HashMap<String, TextureRef>,
     pub elements: Option<Vec<Cube>>,
+    #[serde(default)]
+    pub material: Material,
+    #[serde(default)]
+    pub overrides: Vec<Override>,
 }
 
 impl Model {
     pub fn merge(&mut self, mut parent: Model) {
         self.parent = parent.parent;
         self.ambientocclusion = self.ambientocclusion.or(parent.ambientocclusion);
+        self.gui_light = self.gui_light.or(parent.gui_light);
+        self.render_type = self.render_type.take().or(parent.render_type);
         self.display.merge(parent.display);
         parent.textures.extend(self.textures.drain());
         self.textures = parent.textures;
         if self.elements.is_none() {
             self.elements = parent.elements;
         }
+        self.material.merge(parent.material);
+        // overrides are not inherited from the parent
     }
 
     pub fn ambientocclusion(&self) -> bool { self.ambientocclusion.unwrap_or(true) }
 
+    pub fn gui_light(&self) -> GuiLight { self.gui_light.unwrap_or_default() }
+
+    pub fn material(&self) -> Material {
+        Material { disable_ao: self.material.disable_ao.or(Some(!self.ambientocclusion())), ..self.material }
+    }
+
     pub fn elements(&self) -> &[Cube] { self.elements.as_ref().map(|a| &**a).unwrap_or(&[]) }
 
     pub fn texture(&self, name: &str) -> Option<Identifier> {
@@ -40,6 +56,12 @@ impl Model {
     pub fn is_fully_resolved(&self) -> bool { self.parent.is_none() }
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct Override {
+    pub predicate: BTreeMap<Identifier, f32>,
+    pub model: Identifier,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum TextureRef {
     Literal(Identifier),
@@ -82,6 +104,8 @@ pub struct Cube {
     #[serde(default = "shade_default")]
     pub shade: bool,
     pub faces: Faces,
+    #[serde(default)]
+    pub material: Material,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -184,10 +208,40 @@ pub struct Face {
     #[serde(default)]
     pub rotation: f32,
     pub tintindex: Option<i32>,
+    pub emissive: Option<bool>,
+    #[serde(default)]
+    pub material: Material,
 }
 
 impl Face {
     pub fn tintindex(&self) -> i32 { self.tintindex.unwrap_or(-1) }
+
+    pub fn material(&self) -> Material {
+        Material { emissive: self.material.emissive.or(self.emissive), ..self.material }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Default)]
+pub struct Material {
+    pub blend_mode: Option<BlendMode>,
+    pub emissive: Option<bool>,
+    pub disable_ao: Option<bool>,
+    pub disable_diffuse: Option<bool>,
+}
+
+impl Material {
+    pub fn merge(&mut self, parent: Material) {
+        *self = self.or(parent);
+    }
+
+    pub fn or(self, other: Material) -> Material {
+        Material {
+            blend_mode: self.blend_mode.or(other.blend_mode),
+            emissive: self.emissive.or(other.emissive),
+            disable_ao: self.disable_ao.or(other.disable_ao),
+            disable_diffuse: self.disable_diffuse.or(other.disable_diffuse),
+        }
+    }
 }
 
 pub struct FaceIter<'a> {
@@ -5,10 +5,14 @@ use std::io::Write;
 use byteorder::{LittleEndian, WriteBytesExt};
 
 use crate::ident::Identifier;
-use crate::model::Model;
+use crate::model::{Model, OverrideModel};
 use crate::types::{DisplayTransformation, Vec2, Vec3};
 
-const VERSION: u16 = 3;
+pub const VERSION: u16 = 8;
+
+const FLAG_EMISSIVE: u8 = 0x01;
+const FLAG_DISABLE_AO: u8 = 0x02;
+const FLAG_DISABLE_DIFFUSE: u8 = 0x04;
 
 pub fn write<T: Write>(model: &Model, mut target: T) -> io::Result<()> {
     // file header
@@ -17,12 +21,7 @@ pub fn write<T: Write>(model: &Model, mut target: T) -> io::Result<()> {
 
     // identifier lookup table
     let mut identifiers = HashSet::new();
-    identifiers.insert(&model.particle);
-    for x in &model.meshes {
-        for y in &x.quads {
-            identifiers.insert(&y.texture);
-        }
-    }
+    collect_identifiers(model, &mut identifiers);
     let mut identifiers: Vec<_> = identifiers.into_iter().collect();
     identifiers.sort();
 
@@ -32,17 +31,40 @@ pub fn write<T: Write>(model: &Model, mut target: T) -> io::Result<()> {
         write_identifier(&mut target, x)?;
     }
 
+    write_model(&mut target, model, &identifiers)
+}
+
+fn collect_identifiers<'a>(model: &'a Model, identifiers: &mut HashSet<&'a Identifier>) {
+    identifiers.insert(&model.particle);
+    identifiers.extend(&model.render_type);
+    for x in &model.meshes {
+        for y in &x.quads {
+            identifiers.insert(&y.texture);
+        }
+    }
+    for x in &model.overrides {
+        identifiers.extend(x.predicate.iter().map(|(id, _)| id));
+        if let OverrideModel::Embedded(m) = &x.model {
+            collect_identifiers(m, identifiers);
+        }
+    }
+}
+
+fn write_model<T: Write>(target: &mut T, model: &Model, identifiers: &[&Identifier]) -> io::Result<()> {
     target.write_u16::<LittleEndian>(identifiers.binary_search(&&model.particle).unwrap() as u16)?;
 
     // write transformations
-    write_transformation(&mut target, &model.transformation.thirdperson_righthand)?;
-    write_transformation(&mut target, &model.transformation.thirdperson_lefthand)?;
-    write_transformation(&mut target, &model.transformation.firstperson_righthand)?;
-    write_transformation(&mut target, &model.transformation.firstperson_lefthand)?;
-    write_transformation(&mut target, &model.transformation.gui)?;
-    write_transformation(&mut target, &model.transformation.head)?;
-    write_transformation(&mut target, &model.transformation.ground)?;
-    write_transformation(&mut target, &model.transformation.fixed)?;
+    write_transformation(&mut *target, &model.transformation.thirdperson_righthand)?;
+    write_transformation(&mut *target, &model.transformation.thirdperson_lefthand)?;
+    write_transformation(&mut *target, &model.transformation.firstperson_righthand)?;
+    write_transformation(&mut *target, &model.transformation.firstperson_lefthand)?;
+    write_transformation(&mut *target, &model.transformation.gui)?;
+    write_transformation(&mut *target, &model.transformation.head)?;
+    write_transformation(&mut *target, &model.transformation.ground)?;
+    write_transformation(&mut *target, &model.transformation.fixed)?;
+
+    target.write_u8(model.gui_light.index() as u8)?;
+    target.write_u16::<LittleEndian>(model.render_type.as_ref().map_or(u16::MAX, |id| identifiers.binary_search(&id).unwrap() as u16))?;
 
     // write meshes
     assert!(model.meshes.len() <= u16::MAX as usize);
@@ -53,12 +75,41 @@ pub fn write<T: Write>(model: &Model, mut target: T) -> io::Result<()> {
         for quad in mesh.quads.iter() {
             target.write_u16::<LittleEndian>(identifiers.binary_search(&&quad.texture).unwrap() as u16)?;
             for x in quad.vertices.iter() {
-                write_vec3_fixed_u16(&mut target, x.xyz, -1.5, 2.5)?;
-                write_vec2_fixed_u16(&mut target, x.uv, -0.5, 1.5)?;
+                write_vec3_fixed_u16(&mut *target, x.xyz, -1.5, 2.5)?;
+                write_vec2_fixed_u16(&mut *target, x.uv, -0.5, 1.5)?;
             }
-            write_vec3_fixed_u16(&mut target, quad.normal, -1.0, 1.0)?;
+            write_vec3_fixed_u16(&mut *target, quad.normal, -1.0, 1.0)?;
             target.write_i32::<LittleEndian>(quad.color_index)?;
             target.write_u8(quad.cull_face.map(|d| d.index() as u8).unwrap_or(0xFF))?;
+
+            let mut flags = 0;
+            if quad.material.emissive { flags |= FLAG_EMISSIVE; }
+            if quad.material.disable_ao { flags |= FLAG_DISABLE_AO; }
+            if quad.material.disable_diffuse { flags |= FLAG_DISABLE_DIFFUSE; }
+            target.write_u8(flags)?;
+            target.write_u8(quad.material.blend_mode.index() as u8)?;
+        }
+    }
+
+    // write item overrides
+    assert!(model.overrides.len() <= u16::MAX as usize);
+    target.write_u16::<LittleEndian>(model.overrides.len() as u16)?;
+    for x in model.overrides.iter() {
+        assert!(x.predicate.len() <= u16::MAX as usize);
+        target.write_u16::<LittleEndian>(x.predicate.len() as u16)?;
+        for (id, value) in x.predicate.iter() {
+            target.write_u16::<LittleEndian>(identifiers.binary_search(&id).unwrap() as u16)?;
+            target.write_f32::<LittleEndian>(*value)?;
+        }
+        match &x.model {
+            OverrideModel::Embedded(m) => {
+                target.write_u8(0)?;
+                write_model(target, m, identifiers)?;
+            }
+            OverrideModel::Enclosing(depth) => {
+                target.write_u8(1)?;
+                target.write_u16::<LittleEndian>(*depth)?;
+            }
         }
     }
 
@@ -106,7 +157,7 @@ fn write_f32_fixed_u16<T: Write>(mut target: T, f: f32, min: f32, max: f32) -> i
     Ok(())
 }
 
-fn write_identifier<T: Write>(mut target: T, identifier: &Identifier) -> io::Result<()> {
+pub fn write_identifier<T: Write>(mut target: T, identifier: &Identifier) -> io::Result<()> {
     assert!(identifier.namespace.len() < u16::MAX as usize);
     assert!(identifier.path.len() <= u16::MAX as usize);
     let namespace_len = if identifier.namespace == "minecraft" { u16::MAX } else { identifier.namespace.len() as u16 };